@@ -0,0 +1,195 @@
+use publicsuffix::{List, Psl};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const PUBLIC_SUFFIX_LIST_URL: &str = "https://publicsuffix.org/list/public_suffix_list.dat";
+
+/// A week; publicsuffix.org asks for the list to be downloaded at most once a day, and the suffixes of story sites
+/// rarely change
+const DEFAULT_CACHE_MAX_AGE_HOURS: u64 = 7 * 24;
+
+/// Turns story URLs into the site they belong to, the same way for stories synced and domains filtered on, configured
+/// with
+///
+/// - `PUBLIC_SUFFIX_LIST`: path of a local copy of the public suffix list, kept up to date by the deployment
+/// - `PUBLIC_SUFFIX_CACHE`: where the downloaded list is kept between runs when there is no local copy, by default
+///   `hnstar_public_suffix_list.dat` in the temporary directory
+/// - `PUBLIC_SUFFIX_MAX_AGE_HOURS`: age after which the cached list is downloaded again, a week by default
+/// - `DOMAIN_KEEP_PATH`: comma separated domains whose first path segment names the site, e.g. `github.com`
+pub struct DomainNormalizer {
+    suffixes: Option<List>,
+    keep_path: Vec<String>,
+}
+
+impl DomainNormalizer {
+    pub async fn from_env() -> Self {
+        let keep_path = std::env::var("DOMAIN_KEEP_PATH").unwrap_or_default()
+            .split(',')
+            .map(|d| d.trim().to_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+
+        let list = match std::env::var("PUBLIC_SUFFIX_LIST") {
+            Ok(path) => std::fs::read_to_string(&path).map_err(|e| e.to_string()),
+            Err(_) => cached_list().await,
+        };
+        // Without the list, the last two labels are a good guess for most sites
+        let suffixes = match list.and_then(|list| list.parse::<List>().map_err(|e| e.to_string())) {
+            Ok(list) => Some(list),
+            Err(e) => {
+                eprintln!("could not load public suffix list, using the last two labels of hosts: {}", e);
+                None
+            }
+        };
+
+        DomainNormalizer::new(suffixes, keep_path)
+    }
+
+    /// A normalizer with the public suffix list, or guessing from the last two labels of hosts without it
+    pub fn new(suffixes: Option<List>, keep_path: Vec<String>) -> Self {
+        DomainNormalizer { suffixes, keep_path }
+    }
+
+    /// The lowercased registrable domain of the URL without `www.`, e.g. `bbc.co.uk` for
+    /// `https://www.news.bbc.co.uk/x`, or `github.com/user` when the domain keeps its path.
+    /// Empty when the story has no URL or it cannot be parsed.
+    pub fn normalize(&self, story_url: &str) -> String {
+        let parsed = match url::Url::parse(story_url) {
+            Ok(parsed) => parsed,
+            Err(_) => { return String::new(); }
+        };
+        let host = match parsed.host() {
+            Some(url::Host::Domain(host)) => host.trim_end_matches('.').to_lowercase(),
+            Some(host) => { return host.to_string(); }
+            None => { return String::new(); }
+        };
+
+        let host = host.strip_prefix("www.").unwrap_or(&host);
+        let domain = match &self.suffixes {
+            Some(list) => list.domain(host.as_bytes())
+                .and_then(|d| std::str::from_utf8(d.as_bytes()).ok().map(String::from))
+                .unwrap_or_else(|| String::from(host)),
+            None => {
+                let labels: Vec<&str> = host.rsplitn(3, '.').collect();
+                labels.iter().take(2).rev().cloned().collect::<Vec<_>>().join(".")
+            }
+        };
+
+        if self.keep_path.contains(&domain) {
+            if let Some(segment) = parsed.path_segments().and_then(|mut s| s.next()).filter(|s| !s.is_empty()) {
+                return format!("{}/{}", domain, segment.to_lowercase());
+            }
+        }
+
+        domain
+    }
+}
+
+/// The cached list while it is fresh, otherwise downloaded again, falling back to the stale copy when that fails
+async fn cached_list() -> Result<String, String> {
+    let path = std::env::var("PUBLIC_SUFFIX_CACHE").map(PathBuf::from)
+        .unwrap_or_else(|_| std::env::temp_dir().join("hnstar_public_suffix_list.dat"));
+    let max_age_hours = std::env::var("PUBLIC_SUFFIX_MAX_AGE_HOURS").ok()
+        .and_then(|hours| hours.parse().ok())
+        .unwrap_or(DEFAULT_CACHE_MAX_AGE_HOURS);
+    let max_age = Duration::from_secs(max_age_hours * 60 * 60);
+
+    let age = std::fs::metadata(&path).and_then(|m| m.modified()).ok()
+        .map(|modified| SystemTime::now().duration_since(modified).unwrap_or_default());
+    if let Some(age) = age.filter(|age| *age < max_age) {
+        match std::fs::read_to_string(&path) {
+            Ok(list) => { return Ok(list); }
+            Err(e) => eprintln!("could not read cached public suffix list {} ({:?} old): {}", path.display(), age, e),
+        }
+    }
+
+    match download_list().await {
+        Ok(list) => {
+            if let Err(e) = write_cache(&path, &list) {
+                eprintln!("could not cache public suffix list at {}: {}", path.display(), e);
+            }
+            Ok(list)
+        }
+        Err(e) if age.is_some() => {
+            eprintln!("could not download public suffix list, using the cached copy: {}", e);
+            std::fs::read_to_string(&path).map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the cached list at once, so a concurrent run never reads half of it
+fn write_cache(path: &Path, list: &str) -> std::io::Result<()> {
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, list)?;
+    std::fs::rename(&partial, path)
+}
+
+async fn download_list() -> Result<String, String> {
+    reqwest::get(PUBLIC_SUFFIX_LIST_URL).await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| e.to_string())?
+        .text().await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST: &str = "\
+// ===BEGIN ICANN DOMAINS===
+com
+uk
+co.uk
+// ===END ICANN DOMAINS===
+";
+
+    fn with_list(keep_path: &[&str]) -> DomainNormalizer {
+        DomainNormalizer::new(Some(LIST.parse().unwrap()), keep_path.iter().map(|d| String::from(*d)).collect())
+    }
+
+    #[test]
+    fn lowercases_hosts_and_strips_www() {
+        let domains = with_list(&[]);
+        assert_eq!(domains.normalize("https://Example.COM/Path"), "example.com");
+        assert_eq!(domains.normalize("https://www.example.com/"), "example.com");
+        assert_eq!(domains.normalize("http://example.com./x"), "example.com");
+    }
+
+    #[test]
+    fn registrable_domains() {
+        let domains = with_list(&[]);
+        assert_eq!(domains.normalize("https://news.bbc.co.uk/x"), "bbc.co.uk");
+        assert_eq!(domains.normalize("https://www.news.bbc.co.uk/x"), "bbc.co.uk");
+        assert_eq!(domains.normalize("https://a.b.example.com"), "example.com");
+    }
+
+    #[test]
+    fn keep_path_domains() {
+        let domains = with_list(&["github.com"]);
+        assert_eq!(domains.normalize("https://github.com/User/repo"), "github.com/user");
+        assert_eq!(domains.normalize("https://gist.github.com/user/1"), "github.com/user");
+        assert_eq!(domains.normalize("https://github.com/"), "github.com");
+        assert_eq!(with_list(&[]).normalize("https://github.com/user/repo"), "github.com");
+    }
+
+    #[test]
+    fn urls_without_domains() {
+        let domains = with_list(&[]);
+        assert_eq!(domains.normalize(""), "");
+        assert_eq!(domains.normalize("not a url"), "");
+        assert_eq!(domains.normalize("mailto:someone@example.com"), "");
+        assert_eq!(domains.normalize("http://127.0.0.1:8080/x"), "127.0.0.1");
+        assert_eq!(domains.normalize("http://[::1]/x"), "[::1]");
+    }
+
+    #[test]
+    fn last_two_labels_without_a_list() {
+        let domains = DomainNormalizer::new(None, vec![String::from("github.com")]);
+        assert_eq!(domains.normalize("https://www.news.Example.com/x"), "example.com");
+        assert_eq!(domains.normalize("https://news.bbc.co.uk/x"), "co.uk");
+        assert_eq!(domains.normalize("https://localhost/x"), "localhost");
+        assert_eq!(domains.normalize("https://github.com/user/repo"), "github.com/user");
+    }
+}
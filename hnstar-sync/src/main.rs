@@ -1,4 +1,7 @@
+mod domain;
+
 use chrono::prelude::*;
+use domain::DomainNormalizer;
 use tokio_postgres::{NoTls, Error, Client};
 use serde::Deserialize;
 
@@ -34,54 +37,198 @@ struct Comment {
 
 /// SQL to merge stories into table
 const MERGE_STORY_SQL: &str = "\
-    insert into hnstar.story (story_id, timestamp, by, title, url, descendants, score)
-    values ($1, $2, $3, $4, $5, $6, $7)
+    insert into hnstar.story (story_id, timestamp, by, title, url, descendants, score, domain)
+    values ($1, $2, $3, $4, $5, $6, $7, $8)
     on conflict (story_id)
-    do update set descendants = $6, score = $7";
+    do update set descendants = $6, score = $7, domain = $8";
 
-async fn merge_top_stories(pg_client: &mut Client, stories: &Vec<Story>) {
-    let txn = pg_client.transaction().await.unwrap();
-    let sql = txn.prepare(MERGE_STORY_SQL).await.unwrap();
+async fn merge_top_stories(pg_client: &mut Client, stories: &Vec<Story>,
+                           domains: &DomainNormalizer) -> Result<(), Error> {
+    let txn = pg_client.transaction().await?;
+    let sql = txn.prepare(MERGE_STORY_SQL).await?;
     for story in stories.iter() {
         txn.execute(&sql, &[
             &story.id, &story.time, &story.by, &story.title,
-            &story.url, &story.descendants, &story.score]).await.unwrap();
+            &story.url, &story.descendants, &story.score, &domains.normalize(&story.url)]).await?;
+    }
+    txn.commit().await
+}
+
+/// SQL to recompute the daily statistics of the days of the given stories; earlier days are filled in by the migration.
+/// Log scores are ln(score + 1), matching the z-score log scale of hnstar.
+const REFRESH_DAILY_STATS_SQL: &str = "\
+    with days as (
+        select distinct timestamp / 86400 as day from hnstar.story where story_id = any($1)
+    )
+    insert into hnstar.story_daily_stats (
+        day, story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares, score_quantiles,
+        descendants_sum, descendants_sum_squares, descendants_quantiles, updated)
+    select days.day, count(*)
+        , sum(s.score), sum(cast(s.score as float) ^ 2)
+        , sum(ln(greatest(s.score, 0) + 1)), sum(ln(greatest(s.score, 0) + 1) ^ 2)
+        , percentile_cont(array[0.1, 0.25, 0.5, 0.75, 0.9, 0.99]) within group (order by s.score)
+        , sum(s.descendants), sum(cast(s.descendants as float) ^ 2)
+        , percentile_cont(array[0.1, 0.25, 0.5, 0.75, 0.9, 0.99]) within group (order by s.descendants)
+        , now()
+    from days
+    join hnstar.story s on s.timestamp >= days.day * 86400 and s.timestamp < (days.day + 1) * 86400
+    group by days.day
+    on conflict (day) do update set
+        story_count = excluded.story_count,
+        score_sum = excluded.score_sum,
+        score_sum_squares = excluded.score_sum_squares,
+        log_score_sum = excluded.log_score_sum,
+        log_score_sum_squares = excluded.log_score_sum_squares,
+        score_quantiles = excluded.score_quantiles,
+        descendants_sum = excluded.descendants_sum,
+        descendants_sum_squares = excluded.descendants_sum_squares,
+        descendants_quantiles = excluded.descendants_quantiles,
+        updated = excluded.updated";
+
+async fn refresh_daily_stats(pg_client: &mut Client, stories: &[Story]) -> Result<(), Error> {
+    let story_ids: Vec<i64> = stories.iter().map(|story| story.id).collect();
+    let days = pg_client.execute(REFRESH_DAILY_STATS_SQL, &[&story_ids]).await?;
+    println!("refreshed statistics of {} days", days);
+    Ok(())
+}
+
+/// Derive domains of stories synced before domains were, or whose domain was cleared to derive it again,
+/// e.g. after changing DOMAIN_KEEP_PATH
+async fn backfill_domains(pg_client: &mut Client, domains: &DomainNormalizer) -> Result<(), Error> {
+    loop {
+        let rows = pg_client.query(
+            "select story_id, url from hnstar.story where domain is null limit 1000", &[]).await?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let txn = pg_client.transaction().await?;
+        let sql = txn.prepare("update hnstar.story set domain = $2 where story_id = $1").await?;
+        for row in rows.iter() {
+            let url: Option<&str> = row.get(1);
+            txn.execute(&sql, &[&row.get::<_, i64>(0), &domains.normalize(url.unwrap_or(""))]).await?;
+        }
+        txn.commit().await?;
+        println!("derived domains of {} stories", rows.len());
     }
-    txn.commit().await.unwrap();
 }
 
-async fn get_top_stories() -> Vec<Story> {
-    let client = reqwest::ClientBuilder::new().build().unwrap();
-    let story_ids = client.get("https://hacker-news.firebaseio.com/v0/topstories.json")
-        .send().await.unwrap()
-        .json::<Vec<i64>>()
-        .await.unwrap();
+/// Outcome of a sync run, pushed to a Prometheus Pushgateway when PUSHGATEWAY_URL is set
+#[derive(Default)]
+struct RunMetrics {
+    duration_seconds: f64,
+    items_fetched: u64,
+    failures: u64,
+}
+
+impl RunMetrics {
+    fn to_prometheus_text(&self) -> String {
+        format!("\
+            # TYPE hnstar_sync_run_duration_seconds gauge\n\
+            hnstar_sync_run_duration_seconds {}\n\
+            # TYPE hnstar_sync_items_fetched gauge\n\
+            hnstar_sync_items_fetched {}\n\
+            # TYPE hnstar_sync_failures gauge\n\
+            hnstar_sync_failures {}\n\
+            # TYPE hnstar_sync_last_run_timestamp_seconds gauge\n\
+            hnstar_sync_last_run_timestamp_seconds {}\n",
+            self.duration_seconds, self.items_fetched, self.failures, Utc::now().timestamp())
+    }
 
+    async fn push(&self, pushgateway_url: &str) {
+        let url = format!("{}/metrics/job/hnstar-sync", pushgateway_url.trim_end_matches('/'));
+        let client = match reqwest::ClientBuilder::new().build() {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("pushgateway error: {}", e);
+                return;
+            }
+        };
+        match client.put(&url).body(self.to_prometheus_text()).send().await {
+            Ok(resp) if !resp.status().is_success() => eprintln!("pushgateway responded with {}", resp.status()),
+            Ok(_) => {}
+            Err(e) => eprintln!("pushgateway error: {}", e),
+        }
+    }
+}
+
+async fn get_top_stories(metrics: &mut RunMetrics) -> Result<Vec<Story>, reqwest::Error> {
+    let client = reqwest::ClientBuilder::new().build()?;
+    let story_ids = match client.get("https://hacker-news.firebaseio.com/v0/topstories.json")
+        .send().await
+        .and_then(|resp| resp.error_for_status()) {
+        Ok(resp) => resp.json::<Vec<i64>>().await,
+        Err(e) => Err(e),
+    };
+    let story_ids = match story_ids {
+        Ok(story_ids) => story_ids,
+        Err(e) => {
+            eprintln!("could not get top stories: {}", e);
+            metrics.failures += 1;
+            return Ok(vec![]);
+        }
+    };
+
+    // A single unavailable item should not lose the rest of the run
     let mut stories = Vec::with_capacity(story_ids.len());
     for story_id in story_ids.iter() {
         let url = format!("https://hacker-news.firebaseio.com/v0/item/{}.json", story_id);
-        let story = client.get(&url)
-            .send().await.unwrap()
-            .json::<Story>()
-            .await.unwrap();
-        stories.push(story);
+        let story = match client.get(&url).send().await {
+            Ok(resp) => resp.json::<Story>().await,
+            Err(e) => Err(e),
+        };
+        match story {
+            Ok(story) => {
+                metrics.items_fetched += 1;
+                stories.push(story);
+            }
+            Err(e) => {
+                eprintln!("could not get item {}: {}", story_id, e);
+                metrics.failures += 1;
+            }
+        }
     }
 
-    stories
+    Ok(stories)
 }
 
-#[tokio::main]
-async fn main() {
-    let pg_url = std::env::var("POSTGRESQL_URL").unwrap();
+/// Sync the top stories, counting failures that only lose some of them in the metrics
+async fn sync(metrics: &mut RunMetrics) -> Result<(), Box<dyn std::error::Error>> {
+    let pg_url = std::env::var("POSTGRESQL_URL").map_err(|e| format!("POSTGRESQL_URL: {}", e))?;
 
-    let (mut client, connection) = tokio_postgres::connect(&pg_url, NoTls).await.unwrap();
+    let (mut client, connection) = tokio_postgres::connect(&pg_url, NoTls).await?;
     tokio::spawn(async move {
         if let Err(e) = connection.await {
             eprintln!("connection error: {}", e);
         }
     });
 
-    let top_stories = get_top_stories().await;
+    let domains = DomainNormalizer::from_env().await;
+    backfill_domains(&mut client, &domains).await?;
+
+    let top_stories = get_top_stories(metrics).await?;
     println!("{:?}", top_stories);
-    merge_top_stories(&mut client, &top_stories).await;
+    merge_top_stories(&mut client, &top_stories, &domains).await?;
+    refresh_daily_stats(&mut client, &top_stories).await?;
+    Ok(())
+}
+
+// A failed run still pushes its metrics, so the failure is reported, before exiting unsuccessfully
+#[tokio::main]
+async fn main() {
+    let started = std::time::Instant::now();
+    let mut metrics = RunMetrics::default();
+    let result = sync(&mut metrics).await;
+    if let Err(e) = &result {
+        eprintln!("sync failed: {}", e);
+        metrics.failures += 1;
+    }
+
+    metrics.duration_seconds = started.elapsed().as_secs_f64();
+    if let Ok(pushgateway_url) = std::env::var("PUSHGATEWAY_URL") {
+        metrics.push(&pushgateway_url).await;
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
 }
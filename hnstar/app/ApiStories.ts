@@ -6,12 +6,42 @@ export interface Story {
     timestamp: number;
     title: string;
     url: string;
+    by: string | null;
     status: number;
     descendants: number | null;
     stars: number | null;
     flags: number | null;
+    flagNames: string[];
+    snoozedUntil: number | null;
+    tags: string[];
+    noteCount: number;
+    zScore: number | null;
+    percentile: number | null;
+    domain: string | null;
     key: number;
-    fullCount: number;
+    highlights?: { title: string; note?: string };
+}
+
+export interface StoryCount {
+    value: number;
+    exact: boolean;
+}
+
+export interface WindowStats {
+    // log statistics are of ln(score + 1), like the z-scores
+    scale: "linear" | "log";
+    meanScore: number | null;
+    stddevScore: number | null;
+}
+
+export interface StoriesResponse {
+    version: number;
+    stories: Story[];
+    count?: StoryCount;
+    nextCursor?: string;
+    prevCursor?: string;
+    filter: StoryRankingFilter;
+    stats: WindowStats;
 }
 
 export interface IntFilter {
@@ -30,7 +60,17 @@ export interface FloatFilter {
 }
 
 export interface StoryRankingSort {
-    sort: "timestamp" | "score";
+    sort:
+        | "timestamp"
+        | "score"
+        | "stars"
+        | "by"
+        | "comments"
+        | "zScore"
+        | "commentsPerPoint"
+        | "hot"
+        | "velocity"
+        | "relevance";
     asc: boolean;
 }
 
@@ -39,19 +79,75 @@ export interface PgRegex {
     not: boolean;
 }
 
+export type FilterExpr =
+    | { op: "and" | "or"; terms: FilterExpr[] }
+    | { op: "not"; term: FilterExpr }
+    | { op: "text"; field: "title" | "url" | "domain" | "comment" | "note" | "author"; value: string }
+    | {
+          op: "compare";
+          field: "score" | "comments" | "stars" | "status" | "flags" | "age";
+          cmp: "eq" | "gt" | "ge" | "lt" | "le";
+          value: number;
+      };
+
+export interface FlagBit {
+    name: string;
+    bit: number;
+}
+
+export type FlagsFilter = number | { any?: string[]; all?: string[]; none?: string[] };
+
+export type StatusFilter = number | { in: number[] };
+
+export function getFlagsRequest(): Request {
+    return new Request(API_URL + "ranks/flags", { method: "GET" });
+}
+
+export interface DomainFilter {
+    include?: string[];
+    exclude?: string[];
+}
+
+export interface AuthorFilter {
+    include?: string[];
+    exclude?: string[];
+}
+
+export interface TagFilter {
+    include?: string[];
+    exclude?: string[];
+}
+
 export interface StoryRankingFilter {
     timestamp?: BigIntFilter;
     pageSize: number;
     pageNumber: number;
+    cursor?: string;
+    search?: string;
+    count?: "exact" | "estimate";
+    q?: string;
+    terms?: FilterExpr;
     title?: PgRegex;
     url?: PgRegex;
+    domain?: DomainFilter;
+    by?: AuthorFilter;
+    tags?: TagFilter;
     score?: IntFilter;
     zScore?: FloatFilter;
-    status?: number;
-    flags?: number;
+    zScoreScale?: "linear" | "log";
+    percentile?: FloatFilter;
+    normalization?: "window" | "day" | "rolling24h";
+    status?: StatusFilter;
+    flags?: FlagsFilter;
     stars?: IntFilter;
     comment?: PgRegex;
+    notes?: string;
+    read?: boolean;
+    newSinceLastVisit?: boolean;
+    snoozed?: "hide" | "include" | "active" | "due";
     sort?: StoryRankingSort[];
+    asOf?: number;
+    gravity?: number;
 }
 
 export function getStoriesRequest(filter: StoryRankingFilter): Request {
@@ -64,6 +160,271 @@ export function getStoriesRequest(filter: StoryRankingFilter): Request {
     });
 }
 
+export interface MarkRead {
+    storyIds?: number[];
+    // marks every story the filter matches, not only its page
+    query?: StoryRankingFilter;
+    read?: boolean;
+}
+
+// authorization is the header from getAuthorizationHeader
+export function markReadRequest(mark: MarkRead, authorization: string): Request {
+    return new Request(API_URL + "ranks/read", {
+        method: "POST",
+        body: JSON.stringify(mark),
+        headers: {
+            "Content-Type": "application/json",
+            Authorization: authorization,
+        },
+    });
+}
+
+// until is in seconds since the epoch; null unsnoozes
+export function snoozeRequest(storyIds: number[], until: number | null, authorization: string): Request {
+    return new Request(API_URL + "ranks/snooze", {
+        method: "POST",
+        body: JSON.stringify({ storyIds, until }),
+        headers: {
+            "Content-Type": "application/json",
+            Authorization: authorization,
+        },
+    });
+}
+
+export interface TagStats {
+    name: string;
+    stories: number;
+}
+
+function tagsRequest(path: string, body: object | null, authorization: string): Request {
+    return new Request(API_URL + "tags/" + path, {
+        method: body ? "POST" : "GET",
+        body: body ? JSON.stringify(body) : undefined,
+        headers: {
+            "Content-Type": "application/json",
+            Authorization: authorization,
+        },
+    });
+}
+
+export function getTagsRequest(authorization: string): Request {
+    return tagsRequest("list", null, authorization);
+}
+
+export function addTagsRequest(storyIds: number[], tags: string[], authorization: string): Request {
+    return tagsRequest("add", { storyIds, tags }, authorization);
+}
+
+export function removeTagsRequest(storyIds: number[], tags: string[], authorization: string): Request {
+    return tagsRequest("remove", { storyIds, tags }, authorization);
+}
+
+export function renameTagRequest(from: string, to: string, authorization: string): Request {
+    return tagsRequest("rename", { from, to }, authorization);
+}
+
+export function mergeTagsRequest(from: string[], into: string, authorization: string): Request {
+    return tagsRequest("merge", { from, into }, authorization);
+}
+
+export interface Note {
+    noteId: number;
+    storyId: number;
+    markdown: string;
+    // sanitized on the server, safe to render
+    html: string;
+    created: string;
+    updated: string;
+    revisions: number;
+}
+
+export interface NoteRevision {
+    revision: number;
+    markdown: string;
+    html: string;
+    created: string;
+}
+
+function notesRequest(path: string, body: object, authorization: string): Request {
+    return new Request(API_URL + "notes/" + path, {
+        method: "POST",
+        body: JSON.stringify(body),
+        headers: {
+            "Content-Type": "application/json",
+            Authorization: authorization,
+        },
+    });
+}
+
+export function getNotesRequest(storyId: number, authorization: string): Request {
+    return notesRequest("list", { storyId }, authorization);
+}
+
+export function addNoteRequest(storyId: number, markdown: string, authorization: string): Request {
+    return notesRequest("add", { storyId, markdown }, authorization);
+}
+
+export function editNoteRequest(noteId: number, markdown: string, authorization: string): Request {
+    return notesRequest("edit", { noteId, markdown }, authorization);
+}
+
+export function deleteNoteRequest(noteId: number, authorization: string): Request {
+    return notesRequest("delete", { noteId }, authorization);
+}
+
+export function getNoteHistoryRequest(noteId: number, authorization: string): Request {
+    return notesRequest("history", { noteId }, authorization);
+}
+
+export interface SetStory {
+    story_id: number;
+    stars?: number;
+    flags?: number;
+    comment?: string;
+}
+
+export interface StoryRank {
+    storyId: number;
+    stars: number | null;
+    flags: number | null;
+    flagNames: string[];
+    comment: string | null;
+    created: string;
+    updated: string;
+}
+
+export type SetResult =
+    | { result: "created" | "updated"; rank: StoryRank }
+    | { result: "invalid"; field: string; reason: string };
+
+// allOrNothing writes nothing and fails when an item is invalid, bestEffort writes the valid items
+export function setStoriesRequest(
+    items: SetStory[],
+    mode: "allOrNothing" | "bestEffort",
+    authorization: string
+): Request {
+    return new Request(API_URL + "ranks/set?mode=" + mode, {
+        method: "POST",
+        body: JSON.stringify(items),
+        headers: {
+            "Content-Type": "application/json",
+            Authorization: authorization,
+        },
+    });
+}
+
+function ranksRequest(path: string, body: object, authorization: string): Request {
+    return new Request(API_URL + "ranks/" + path, {
+        method: "POST",
+        body: JSON.stringify(body),
+        headers: {
+            "Content-Type": "application/json",
+            Authorization: authorization,
+        },
+    });
+}
+
+export type ClearableField = "stars" | "flags" | "comment" | "snoozedUntil";
+
+// resets the fields to the values of a new ranking: no stars, flags or comment, not snoozed
+export function clearRanksRequest(storyIds: number[], fields: ClearableField[], authorization: string): Request {
+    return ranksRequest("clear", { storyIds, fields }, authorization);
+}
+
+export function deleteRanksRequest(storyIds: number[], authorization: string): Request {
+    return ranksRequest("delete", { storyIds }, authorization);
+}
+
+// the stored ranking row, keyed by column name
+export interface LoggedRank {
+    story_id: number;
+    stars: number | null;
+    flags: number | null;
+    comment: string | null;
+    created: string;
+    updated: string;
+    snoozed_until: string | null;
+}
+
+export interface RankChange {
+    logId: number;
+    storyId: number;
+    // null when the change created the ranking
+    before: LoggedRank | null;
+    // null when the change deleted the ranking
+    after: LoggedRank | null;
+    undoes: number | null;
+    undone: boolean;
+    created: string;
+}
+
+export function getRankLogRequest(limit: number, beforeLogId: number | undefined, authorization: string): Request {
+    return ranksRequest("log", { limit, beforeLogId }, authorization);
+}
+
+export interface UndoneChange {
+    logId: number;
+    storyId: number;
+    // null when undoing removed the ranking
+    rank: StoryRank | null;
+}
+
+// every story a request changed is one change, so undoing a bulk request takes as many
+export function undoRanksRequest(count: number, authorization: string): Request {
+    return ranksRequest("undo", { count }, authorization);
+}
+
+export interface AuthorStatsFilter {
+    timestamp?: BigIntFilter;
+    by?: string[];
+    minStories?: number;
+    limit?: number;
+    topStories?: number;
+}
+
+export interface AuthorStats {
+    by: string;
+    stories: number;
+    medianScore: number;
+    topStories: { storyId: number; title: string; score: number; timestamp: number }[];
+}
+
+export function getAuthorsRequest(filter: AuthorStatsFilter): Request {
+    return new Request(API_URL + "authors/query", {
+        method: "POST",
+        body: JSON.stringify(filter),
+        headers: {
+            "Content-Type": "application/json",
+        },
+    });
+}
+
+export interface DailyStats {
+    timestamp: number;
+    count: number;
+    meanScore: number | null;
+    stddevScore: number | null;
+    scoreQuantiles: number[];
+    meanDescendants: number | null;
+    stddevDescendants: number | null;
+    descendantsQuantiles: number[];
+}
+
+export interface DailyStatsResponse {
+    quantiles: number[];
+    days: DailyStats[];
+}
+
+export function getDailyStatsRequest(timestamp?: BigIntFilter): Request {
+    return new Request(API_URL + "stats/daily", {
+        method: "POST",
+        body: JSON.stringify({ timestamp }),
+        headers: {
+            "Content-Type": "application/json",
+        },
+    });
+}
+
 export function validateStory(story: Story): string | null {
     if (!story) return "Invalid story: no data";
     if (typeof story.storyId !== "number") return "Invalid story: bad story_id";
@@ -3,6 +3,7 @@ import NavigationBar from "./NavigationBar";
 import PageContent from "./PageContent";
 import {
     getStoriesRequest,
+    StoriesResponse,
     Story,
     StoryRankingFilter,
     validateNumberFilter,
@@ -104,19 +105,19 @@ class App extends React.Component<any, AppState> {
             const response = await fetch(request);
             if (response.status !== 200) throw `Status indicates failure: ${response.status}`;
 
-            const rawStories = (await response.json()) as Story[];
+            const data = (await response.json()) as StoriesResponse;
             const stories: Story[] = [];
-            for (const story of rawStories) {
+            for (const story of data.stories) {
                 const error = validateStory(story);
                 if (error) throw `Invalid story: ${JSON.stringify(story)}`;
 
                 stories.push(story);
             }
 
-            this.setState({ ...this.state, stories, loading: false });
+            this.setState({ ...this.state, stories, count: data.count?.value || 0, loading: false });
         } catch (e) {
             console.error(e);
-            this.setState({ ...this.state, stories: [], loading: false });
+            this.setState({ ...this.state, stories: [], count: 0, loading: false });
             alert(`Could not get stories: ${e}`);
         }
     };
@@ -144,6 +145,7 @@ class App extends React.Component<any, AppState> {
         const defaultFilter: StoryRankingFilter = {
             pageSize: validatePageSize(stickySettings.pageSize) || 50,
             pageNumber: 0,
+            count: "estimate",
             zScore: ssZScore,
             sort: validateSorts(stickySettings.sorts) || [{ sort: "timestamp", asc: false }],
         };
@@ -161,6 +163,7 @@ class App extends React.Component<any, AppState> {
 
         this.state = {
             stories: [],
+            count: 0,
             dateDisplay: stickySettings.dateDisplay || { of: "distance" },
             dateRange: stickySettings.dateRange || { of: "week" },
             darkTheme: stickySettings.darkTheme || false,
@@ -202,6 +205,7 @@ class App extends React.Component<any, AppState> {
                 <ProgressBar className={"gray-progress-bar"} animated={this.state.loading} now={100} />
                 <PageContent
                     stories={this.state.stories}
+                    count={this.state.count}
                     page={{ size: this.state.filter.pageSize, number: this.state.filter.pageNumber }}
                     dateDisplay={this.state.dateDisplay}
                     setPage={this.setPage}
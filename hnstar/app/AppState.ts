@@ -92,4 +92,5 @@ export interface AppState {
     username?: string;
     expires?: Date;
     stories: Story[];
+    count: number;
 }
@@ -6,6 +6,7 @@ import { DateDisplay } from "./AppState";
 
 interface PageContentProps {
     stories: Story[];
+    count: number;
     page: { size: number; number: number };
     setPage: (pageSize: number, pageNumber: number) => void;
     dateDisplay: DateDisplay;
@@ -13,15 +14,9 @@ interface PageContentProps {
 }
 
 function PageContent(props: PageContentProps) {
-    const { stories, dateDisplay, page, setPage, loading } = props;
+    const { stories, count, dateDisplay, page, setPage, loading } = props;
     const hnUrl = (id: number) => `https://news.ycombinator.com/item?id=${id}`;
-    const urlSource = (url: string) => {
-        try {
-            return `(${new URL(url).hostname})`;
-        } catch {
-            return "";
-        }
-    };
+    const urlSource = (story: Story) => (story.domain ? `(${story.domain})` : "");
 
     const onClickPage = (pageNumber: number) => {
         console.log(pageNumber);
@@ -67,7 +62,7 @@ function PageContent(props: PageContentProps) {
     // Pagination
     const active = page.number;
     const items = [];
-    const pageCount = Math.max(0, Math.ceil((stories.length ? stories[0].fullCount : 0) / page.size) - 1);
+    const pageCount = Math.max(0, Math.ceil(count / page.size) - 1);
     if (pageCount > 0 && active >= 4) {
         items.push(
             <Pagination.Item key={0} onClick={() => onClickPage(0)} disabled={loading}>
@@ -159,7 +154,7 @@ function PageContent(props: PageContentProps) {
                                 >
                                     <span>{story.title + " "}</span>
                                     <span className="source">
-                                        {urlSource(story.url)} {distance(story.timestamp)}
+                                        {urlSource(story)} {distance(story.timestamp)}
                                     </span>
                                 </a>
                             </div>
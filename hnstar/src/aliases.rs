@@ -1,4 +1,5 @@
 use deadpool_postgres::{ClientWrapper, Pool};
 
-pub type PgConn = deadpool::managed::Object<ClientWrapper, tokio_postgres::error::Error>;
+pub type PooledConn = deadpool::managed::Object<ClientWrapper, tokio_postgres::error::Error>;
+pub type PgConn = crate::query_guard::GuardedConn;
 pub type PgPool = Pool;
@@ -1,5 +1,32 @@
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse, http};
+use crate::request_id::RequestId;
+use serde::Serialize;
 use std::fmt;
+use std::time::Duration;
+
+/// Why the authentication service refused a bearer token
+#[derive(Debug, Copy, Clone)]
+pub enum TokenRejection {
+    Expired,
+    Invalid,
+}
+
+impl TokenRejection {
+    /// Machine-readable reason sent back in the WWW-Authenticate header
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TokenRejection::Expired => "token_expired",
+            TokenRejection::Invalid => "token_invalid",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            TokenRejection::Expired => "Token expired, sign in again",
+            TokenRejection::Invalid => "Token invalid",
+        }
+    }
+}
 
 pub enum WebError {
     Tokio(tokio_postgres::Error),
@@ -7,7 +34,16 @@ pub enum WebError {
     SerdeJson(serde_json::Error),
     ReqwestError(reqwest::Error),
     Invalid(String),
+    /// A validation failure of the field at the given path, e.g. `[2].stars`
+    Validation(String, String),
     Unauthorized(String),
+    /// A rejected token, with details for the server log only
+    TokenRejected(TokenRejection, String),
+    AuthUnavailable(String),
+    /// A server-side failure, such as bad configuration, with details for the server log only
+    Internal(String),
+    /// The query ran into the statement timeout
+    QueryTimeout(Duration),
 }
 
 impl From<serde_json::Error> for WebError {
@@ -34,18 +70,92 @@ impl fmt::Debug for WebError {
             WebError::SerdeJson(e) => e.fmt(f),
             WebError::ReqwestError(e) => e.fmt(f),
             WebError::Invalid(e) => e.fmt(f),
+            WebError::Validation(field, e) => write!(f, "{}: {:?}", field, e),
             WebError::Unauthorized(e) => e.fmt(f),
+            WebError::TokenRejected(r, e) => write!(f, "{}: {:?}", r.reason(), e),
+            WebError::AuthUnavailable(e) => e.fmt(f),
+            WebError::Internal(e) => e.fmt(f),
+            WebError::QueryTimeout(t) => write!(f, "query timed out after {:?}", t),
         }
     }
 }
 
+#[derive(Serialize)]
+struct ErrorEnvelope<'a> {
+    error: ErrorBody<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'a str>,
+    #[serde(rename = "requestId")]
+    request_id: &'a str,
+}
+
 impl WebError {
-    pub fn to_response(&self) -> HttpResponse {
+    /// Stable code clients can match on, independent of the message wording
+    pub fn code(&self) -> &'static str {
         match self {
-            WebError::Invalid(err) => HttpResponse::BadRequest().body(err),
-            WebError::Unauthorized(err) => HttpResponse::Unauthorized().body(err),
-            err => HttpResponse::InternalServerError().body(format!("{:?}", err))
+            WebError::Invalid(_) => "invalid_request",
+            WebError::Validation(_, _) => "validation_failed",
+            WebError::Unauthorized(_) => "unauthorized",
+            WebError::TokenRejected(rejection, _) => rejection.reason(),
+            WebError::AuthUnavailable(_) => "auth_unavailable",
+            WebError::QueryTimeout(_) => "query_timeout",
+            _ => "internal_error",
         }
     }
-}
 
+    fn status(&self) -> http::StatusCode {
+        match self {
+            WebError::Invalid(_) | WebError::Validation(_, _) => http::StatusCode::BAD_REQUEST,
+            WebError::Unauthorized(_) | WebError::TokenRejected(_, _) => http::StatusCode::UNAUTHORIZED,
+            WebError::AuthUnavailable(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+            // the database gave up on the query, which the client may retry narrower
+            WebError::QueryTimeout(_) => http::StatusCode::GATEWAY_TIMEOUT,
+            _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Message safe to show to clients; details of internal failures only go to the server log
+    fn message(&self) -> String {
+        match self {
+            WebError::Invalid(err) => err.clone(),
+            WebError::Validation(_, err) => err.clone(),
+            WebError::Unauthorized(err) => err.clone(),
+            WebError::TokenRejected(rejection, _) => String::from(rejection.message()),
+            WebError::AuthUnavailable(_) => String::from("Authentication service unavailable"),
+            WebError::QueryTimeout(t) => format!(
+                "Query took longer than {} ms; simplify the regular expressions or narrow the timestamp range",
+                t.as_millis()),
+            _ => String::from("Internal server error"),
+        }
+    }
+
+    pub fn to_response(&self, req: &HttpRequest) -> HttpResponse {
+        let request_id = RequestId::of(req);
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!(request_id = %request_id, error = ?self, "request failed");
+        }
+
+        let field = match self {
+            WebError::Validation(field, _) => Some(field.as_str()),
+            _ => None,
+        };
+        let envelope = ErrorEnvelope {
+            error: ErrorBody { code: self.code(), message: &self.message(), field, request_id: &request_id },
+        };
+
+        let mut response = HttpResponse::build(status);
+        if let WebError::TokenRejected(rejection, _) = self {
+            response.insert_header((http::header::WWW_AUTHENTICATE, format!(
+                "Bearer error=\"invalid_token\", error_description=\"{}\"", rejection.reason())));
+        }
+
+        response.json(envelope)
+    }
+}
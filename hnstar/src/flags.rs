@@ -0,0 +1,98 @@
+//! Named bits of the `flags` a user sets on a story, and the filters over them.
+
+use crate::error_util::WebError;
+use crate::sql_builder::{Parameters, Predicate};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct FlagBit {
+    pub name: &'static str,
+    pub bit: i32,
+}
+
+/// The read state of a story, set by `/ranks/read`, which also records when the user last marked stories read
+pub const READ_FLAG: i32 = 2;
+
+/// Every flag the client can set; bits not listed here are kept but have no name
+pub const FLAG_BITS: &[FlagBit] = &[
+    FlagBit { name: "hidden", bit: 1 },
+    FlagBit { name: "read", bit: READ_FLAG },
+    FlagBit { name: "saved", bit: 4 },
+];
+
+/// Names of the bits set in the flags, in bit order
+pub fn flag_names(flags: i32) -> Vec<&'static str> {
+    FLAG_BITS.iter().filter(|f| flags & f.bit != 0).map(|f| f.name).collect()
+}
+
+/// The bits of the named flags, or the field and name of the first unknown one
+fn flag_mask(field: &str, names: &[String]) -> Result<i32, WebError> {
+    names.iter().try_fold(0, |mask, name| {
+        let flag = FLAG_BITS.iter().find(|f| f.name == name.as_str()).ok_or_else(|| WebError::Validation(
+            String::from(field),
+            format!("Unknown flag '{}', expected one of {}", name,
+                    FLAG_BITS.iter().map(|f| f.name).collect::<Vec<_>>().join(", "))))?;
+        Ok(mask | flag.bit)
+    })
+}
+
+/// Flags equal to a number, or having any, all or none of the named flags. Stories without a ranking have no flags.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum FlagsFilter {
+    Exact(i32),
+    Named {
+        any: Option<Vec<String>>,
+        all: Option<Vec<String>>,
+        none: Option<Vec<String>>,
+    },
+}
+
+impl FlagsFilter {
+    /// Predicate over the flags `column`, binding the masks as parameters
+    pub fn to_sql(&self, column: &str, parameters: &mut Parameters) -> Result<Predicate, WebError> {
+        let (any, all, none) = match self {
+            FlagsFilter::Exact(flags) => {
+                return Ok(Predicate::sql(format!("{} = {}", column, parameters.bind(*flags))));
+            }
+            FlagsFilter::Named { any, all, none } => (any, all, none),
+        };
+
+        let flags = format!("coalesce({}, 0)", column);
+        let mut terms = vec![];
+        if let Some(any) = any.as_ref().filter(|a| !a.is_empty()) {
+            let mask = parameters.bind(flag_mask("flags.any", any)?);
+            terms.push(Predicate::sql(format!("{} & {} <> 0", flags, mask)));
+        }
+        if let Some(all) = all.as_ref().filter(|a| !a.is_empty()) {
+            let mask = parameters.bind(flag_mask("flags.all", all)?);
+            terms.push(Predicate::sql(format!("{f} & {m} = {m}", f = flags, m = mask)));
+        }
+        if let Some(none) = none.as_ref().filter(|n| !n.is_empty()) {
+            let mask = parameters.bind(flag_mask("flags.none", none)?);
+            terms.push(Predicate::sql(format!("{} & {} = 0", flags, mask)));
+        }
+
+        Ok(Predicate::All(terms))
+    }
+}
+
+/// Story status equal to a number or one of a list
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum StatusFilter {
+    Exact(i32),
+    In {
+        #[serde(rename = "in")]
+        values: Vec<i32>,
+    },
+}
+
+impl StatusFilter {
+    pub fn to_sql(&self, column: &str, parameters: &mut Parameters) -> Predicate {
+        match self {
+            StatusFilter::Exact(status) => Predicate::sql(format!("{} = {}", column, parameters.bind(*status))),
+            StatusFilter::In { values } => Predicate::sql(format!("{} = any({})", column, parameters.bind(values.clone()))),
+        }
+    }
+}
@@ -1,17 +1,44 @@
 mod aliases;
+mod cursor;
+// shared with hnstar-sync, so domains in filters are normalized exactly like those of synced stories
+#[path = "../../hnstar-sync/src/domain.rs"]
+mod domain;
 mod error_util;
-
-use actix_web::{App, error, HttpRequest, HttpResponse, HttpServer, post, Responder, web, dev, http};
+mod flags;
+mod markdown;
+mod metrics;
+mod metrics_access;
+mod query_guard;
+mod query_language;
+#[cfg(test)]
+mod query_snapshots;
+mod request_id;
+mod sql_builder;
+
+use actix_web::{App, error, get, HttpMessage, HttpRequest, HttpResponse, HttpServer, post, Responder, web, dev, http};
+use actix_web::dev::Service;
 use aliases::*;
+use cursor::{CursorValue, StoryCursor};
 use chrono::{Utc, Duration, DateTime, NaiveDateTime};
 use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
-use error_util::WebError;
-use std::net::{ToSocketAddrs, IpAddr};
+use domain::DomainNormalizer;
+use error_util::{TokenRejection, WebError};
+use flags::{FlagsFilter, StatusFilter};
+use metrics::Metrics;
+use metrics_access::MetricsAccess;
+use query_guard::{CancelOnDrop, GuardedConn, QueryLimits};
+use query_language::FilterExpr;
+use request_id::RequestId;
+use sql_builder::{Comparison, Join, Parameters, Placeholder, Predicate, Select, SqlParameter};
+use std::net::ToSocketAddrs;
 use tokio_postgres::NoTls;
 use serde::{Deserialize, Serialize};
-use postgres_types::ToSql;
 use actix_web_opentelemetry::RequestMetrics;
 use opentelemetry::global;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
 
 pub fn time_to_json(t: NaiveDateTime) -> String {
     DateTime::<Utc>::from_utc(t, Utc).to_rfc3339()
@@ -55,54 +82,114 @@ struct AppState {
     pool: PgPool,
     auth_url: String,
     auth_client: reqwest::Client,
+    limits: QueryLimits,
+    metrics: Arc<Metrics>,
+    domains: Arc<DomainNormalizer>,
 }
 
 impl AppState {
     async fn conn(&self) -> Result<PgConn, WebError> {
-        Ok(self.pool.get().await?)
+        Ok(GuardedConn::new(self.pool.get().await?))
     }
 
+    #[tracing::instrument(name = "authenticate", skip(self, req, data), fields(user_id))]
     async fn authenticate(&self, req: &HttpRequest, data: &web::Data<AppState>) -> Result<AuthenticatedConnection, WebError> {
-        let auth_header = req.headers().get("Authorization")
-            .and_then(|h| h.to_str().ok())
-            .ok_or(WebError::Unauthorized(String::from("Authorization header missing")))?;
-        let auth_req_url = match reqwest::Url::parse(format!("{}/user/get", data.auth_url).as_str()) {
-            Ok(auth_req_url) => auth_req_url,
-            Err(_) => { return Err(WebError::Invalid(format!("Error parsing authentication URL {}", data.auth_url))); }
+        let auth_header = req.headers().get(http::header::AUTHORIZATION)
+            .ok_or(WebError::Unauthorized(String::from("Authorization header missing")))?
+            .to_str()
+            .map_err(|_| WebError::TokenRejected(
+                TokenRejection::Invalid, String::from("Authorization header is not valid")))?;
+        let auth_req_url = data.auth_service_url("user/get")?;
+
+        let started = Instant::now();
+        let result = self.request_user(auth_req_url, auth_header).await;
+        let outcome = match &result {
+            Ok(_) => "ok",
+            Err(WebError::TokenRejected(_, _)) => "rejected",
+            Err(_) => "unavailable",
         };
+        self.metrics.record_auth(started.elapsed(), outcome);
+
+        let user = result?;
+        tracing::Span::current().record("user_id", user.user_id);
+        let conn = self.conn().await?;
+        Ok(AuthenticatedConnection { user, conn })
+    }
+
+    /// URL of a path of the authentication service; a bad configured URL is logged but never shown to clients
+    fn auth_service_url(&self, path: &str) -> Result<reqwest::Url, WebError> {
+        reqwest::Url::parse(format!("{}/{}", self.auth_url, path).as_str())
+            .map_err(|err| WebError::Internal(format!("Error parsing authentication URL {}: {}", self.auth_url, err)))
+    }
+
+    /// Authenticate when credentials are given; only a request without any is anonymous,
+    /// so a rejected token does not silently hide stars
+    async fn authenticate_or_anonymous(&self, req: &HttpRequest, data: &web::Data<AppState>) -> Result<AuthenticatedConnection, WebError> {
+        if req.headers().contains_key(http::header::AUTHORIZATION) {
+            return self.authenticate(req, data).await;
+        }
+
+        Ok(AuthenticatedConnection {
+            conn: self.conn().await?,
+            user: UserData {
+                status: 0,
+                user_id: -1,
+                username: String::from("default"),
+                email: None,
+                name: None,
+                created: chrono::Utc::now().naive_utc(),
+                updated: chrono::Utc::now().naive_utc(),
+            },
+        })
+    }
 
-        let auth_resp = data.auth_client
+    async fn request_user(&self, auth_req_url: reqwest::Url, auth_header: &str) -> Result<UserData, WebError> {
+        let auth_resp = self.auth_client
             .post(auth_req_url)
             .header(reqwest::header::AUTHORIZATION, auth_header)
-            .send().await?
-            .json::<UserData>().await?;
-
-        let response: Option<UserData> = Some(auth_resp);
-        if let Some(user) = response {
-            let conn = self.conn().await?;
-            Ok(AuthenticatedConnection { user, conn })
-        } else {
-            Err(WebError::Unauthorized(String::from("Not authenticated")))
+            .send().await
+            .map_err(|err| WebError::AuthUnavailable(format!("Authentication service unreachable: {}", err)))?;
+
+        let status = auth_resp.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            // the body only goes to the server log, clients get the rejection's code
+            let body = auth_resp.text().await.unwrap_or_default();
+            let rejection = match serde_json::from_str::<AuthRejection>(&body) {
+                Ok(AuthRejection { reason: Some(reason) }) if reason == "expired" || reason == "token_expired" =>
+                    TokenRejection::Expired,
+                _ => TokenRejection::Invalid,
+            };
+            tracing::info!(status = %status, body = %body, reason = rejection.reason(), "token rejected");
+            return Err(WebError::TokenRejected(rejection, body));
+        } else if !status.is_success() {
+            return Err(WebError::AuthUnavailable(format!("Authentication service responded with {}", status)));
         }
+
+        auth_resp.json::<UserData>().await
+            .map_err(|err| WebError::AuthUnavailable(format!("Invalid authentication service response: {}", err)))
     }
 }
 
+/// Why the authentication service rejected a token, when it says
+#[derive(Deserialize)]
+struct AuthRejection {
+    reason: Option<String>,
+}
+
 #[post("/authenticate/{mode}")]
 async fn authenticate(req: HttpRequest, data: web::Data<AppState>, body: web::Bytes, mode: web::Path<String>) -> impl Responder {
     let m = mode.as_str();
     if m == "mirror" {
-        let r = body.to_vec();
-        let b = String::from_utf8(r).unwrap();
-        return HttpResponse::Ok().body(b);
+        return match String::from_utf8(body.to_vec()) {
+            Ok(b) => HttpResponse::Ok().body(b),
+            Err(_) => WebError::Invalid(String::from("Body is not valid UTF-8")).to_response(&req),
+        };
     }
 
     let response = if m == "sign_in" || m == "sign_up" {
-        let auth_req_url = match reqwest::Url::parse(format!("{}/anonymous/{}", data.auth_url, m).as_str()) {
+        let auth_req_url = match data.auth_service_url(&format!("anonymous/{}", m)) {
             Ok(auth_req_url) => auth_req_url,
-            Err(_) => {
-                return WebError::Invalid(format!("Error parsing authentication URL {}", data.auth_url))
-                    .to_response();
-            }
+            Err(err) => { return err.to_response(&req); }
         };
 
         let resp = match data.auth_client
@@ -111,7 +198,7 @@ async fn authenticate(req: HttpRequest, data: web::Data<AppState>, body: web::By
             .body(body)
             .send().await {
             Ok(resp) => resp,
-            Err(err) => { return WebError::from(err).to_response(); }
+            Err(err) => { return WebError::from(err).to_response(&req); }
         };
 
         Some(resp)
@@ -120,17 +207,12 @@ async fn authenticate(req: HttpRequest, data: web::Data<AppState>, body: web::By
             .and_then(|h| h.to_str().ok())
             .ok_or(WebError::Unauthorized(String::from("Authorization header missing"))) {
             Ok(auth_header) => auth_header,
-            Err(_) => {
-                return HttpResponse::Unauthorized().body("No authorization header found");
-            }
+            Err(err) => { return err.to_response(&req); }
         };
 
-        let auth_req_url = match reqwest::Url::parse(format!("{}/user/{}", data.auth_url, m).as_str()) {
+        let auth_req_url = match data.auth_service_url(&format!("user/{}", m)) {
             Ok(auth_req_url) => auth_req_url,
-            Err(_) => {
-                return WebError::Invalid(format!("Error parsing authentication URL {}", data.auth_url))
-                    .to_response();
-            }
+            Err(err) => { return err.to_response(&req); }
         };
 
         let resp = match data.auth_client
@@ -138,7 +220,7 @@ async fn authenticate(req: HttpRequest, data: web::Data<AppState>, body: web::By
             .header(reqwest::header::AUTHORIZATION, auth_header)
             .send().await {
             Ok(resp) => resp,
-            Err(err) => { return WebError::from(err).to_response(); }
+            Err(err) => { return WebError::from(err).to_response(&req); }
         };
 
         Some(resp)
@@ -148,14 +230,21 @@ async fn authenticate(req: HttpRequest, data: web::Data<AppState>, body: web::By
 
     if let Some(response) = response {
         let status = response.status();
+        let text = match response.text().await {
+            Ok(text) => text,
+            Err(err) => {
+                return WebError::AuthUnavailable(format!("Invalid authentication service response: {}", err))
+                    .to_response(&req);
+            }
+        };
         return if status == 200 {
-            HttpResponse::Ok().body(response.text().await.unwrap())
+            HttpResponse::Ok().body(text)
         } else if status == 401 {
-            HttpResponse::Unauthorized().body(response.text().await.unwrap())
+            HttpResponse::Unauthorized().body(text)
         } else if status == 500 {
-            HttpResponse::InternalServerError().body(response.text().await.unwrap())
+            HttpResponse::InternalServerError().body(text)
         } else {
-            HttpResponse::BadRequest().body(format!("Status: {} = {}", status, response.text().await.unwrap()))
+            HttpResponse::BadRequest().body(format!("Status: {} = {}", status, text))
         };
     } else {
         HttpResponse::NotFound().finish()
@@ -171,427 +260,2397 @@ struct SetStory {
 }
 
 impl SetStory {
-    fn is_valid(&self) -> Result<(), String> {
+    /// Validate, returning the offending field and why on failure
+    fn is_valid(&self) -> Result<(), (&'static str, String)> {
         let invalid_stars = if let Some(v) = self.stars { v < 0 || v > 10 } else { false };
         let invalid_flags = if let Some(f) = self.flags { f < 0 } else { false };
         if self.story_id <= 0 {
-            Err(String::from("Invalid story_id"))
+            Err(("story_id", String::from("Invalid story_id")))
         } else if invalid_stars {
-            Err(String::from("Invalid star count"))
+            Err(("stars", String::from("Invalid star count, must be between 0 and 10")))
         } else if invalid_flags {
-            Err(String::from("Invalid flags"))
+            Err(("flags", String::from("Invalid flags, must not be negative")))
         } else {
             Ok(())
         }
     }
 }
 
-async fn do_set_story_ranking(auth: &mut AuthenticatedConnection, model: &Vec<SetStory>) -> Result<String, WebError> {
-    let txn = auth.conn.transaction().await?;
-    for set in model.iter() {
-        if let Err(err) = set.is_valid() {
-            return Err(WebError::Invalid(err));
-        }
-
-        // TODO: add clause to verify story_id exists
-        let sql = "
-                    insert into hnstar.story_user_rank (user_main_id, story_id, stars, flags, comment, created, updated)
-                    values ($1, $2, coalesce($3, 0), coalesce($4, 0), coalesce($5, ''), now(), now())
-                    on conflict (user_main_id, story_id)
-                    do update set
-                        stars = coalesce($3, hnstar.story_user_rank.stars),
-                        flags = coalesce($4, hnstar.story_user_rank.flags),
-                        comment = coalesce($5, hnstar.story_user_rank.comment),
-                        updated = now()";
-        txn.execute(sql, &[&auth.user.user_id, &set.story_id, &set.stars, &set.flags, &set.comment]).await?;
-    }
-
-    Ok(String::from("All good"))
-}
-
-#[post("/set")]
-async fn set_story_ranking(req: HttpRequest, data: web::Data<AppState>, model: web::Json<Vec<SetStory>>) -> impl Responder {
-    let mut auth = match data.authenticate(&req, &data).await {
-        Ok(auth) => auth,
-        Err(err) => { return err.to_response(); }
-    };
-
-    let result = do_set_story_ranking(&mut auth, &model).await;
-    match result {
-        Ok(json) => HttpResponse::Ok().body(json),
-        Err(err) => err.to_response()
-    }
-}
-
-#[derive(Deserialize, Copy, Clone)]
-struct IntFilter {
-    gt: Option<i32>,
-    lt: Option<i32>,
-}
-
-#[derive(Deserialize, Copy, Clone)]
-struct BigIntFilter {
-    gt: Option<i64>,
-    lt: Option<i64>,
-}
-
-#[derive(Deserialize, Copy, Clone)]
-struct FloatFilter {
-    gt: Option<f64>,
-    lt: Option<f64>,
-}
-
-#[derive(Deserialize, Clone)]
-struct StoryRankingSort {
-    sort: String,
-    asc: bool,
-}
-
-#[derive(Deserialize, Clone)]
-struct PgRegex {
-    regex: String,
-    not: bool,
+/// Whether /ranks/set writes nothing when an item is invalid, or every valid item
+#[derive(Deserialize, Copy, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum SetMode {
+    AllOrNothing,
+    BestEffort,
 }
 
 #[derive(Deserialize)]
-struct StoryRankingFilter {
-    timestamp: Option<BigIntFilter>,
-    #[serde(rename = "pageSize")]
-    page_size: Option<i32>,
-    #[serde(rename = "pageNumber")]
-    page_number: Option<i32>,
-    title: Option<PgRegex>,
-    url: Option<PgRegex>,
-    score: Option<IntFilter>,
-    #[serde(rename = "zScore")]
-    z_score: Option<FloatFilter>,
-    status: Option<i32>,
-    flags: Option<i32>,
-    stars: Option<IntFilter>,
-    comment: Option<PgRegex>,
-    sort: Option<Vec<StoryRankingSort>>,
+struct SetOptions {
+    mode: Option<SetMode>,
 }
 
+/// A user's ranking of a story as stored
 #[derive(Serialize)]
-struct GetStory {
+struct StoryRank {
     #[serde(rename = "storyId")]
     story_id: i64,
-    score: i32,
-    timestamp: i64,
-    title: String,
-    url: String,
-    status: i32,
-    descendants: i32,
     stars: Option<i32>,
     flags: Option<i32>,
-    #[serde(rename = "fullCount")]
-    full_count: i32,
+    #[serde(rename = "flagNames")]
+    flag_names: Vec<&'static str>,
+    comment: Option<String>,
+    #[serde(with = "json_time")]
+    created: NaiveDateTime,
+    #[serde(with = "json_time")]
+    updated: NaiveDateTime,
 }
 
-impl From<&tokio_postgres::row::Row> for GetStory {
+const RANK_COLUMNS: &str = "r.story_id, r.stars, r.flags, r.comment, r.created, r.updated";
+
+impl From<&tokio_postgres::row::Row> for StoryRank {
     fn from(row: &tokio_postgres::row::Row) -> Self {
-        let story_id = row.get(0);
-        let score = row.get(1);
-        let timestamp = row.get(2);
-        let title = row.get(3);
-        let url = row.get(4);
-        let status = row.get(5);
-        let descendants = row.get(6);
-        let stars = row.get(7);
-        let flags = row.get(8);
-        let full_count = row.get(9);
-        GetStory {
-            story_id,
-            score,
-            timestamp,
-            title: htmlescape::decode_html(title)
-                .map_or_else(|_| String::from(title), |v| v),
-            url,
-            status,
-            descendants,
-            stars,
+        let flags: Option<i32> = row.get(2);
+        StoryRank {
+            story_id: row.get(0),
+            stars: row.get(1),
             flags,
-            full_count,
+            flag_names: flags::flag_names(flags.unwrap_or(0)),
+            comment: row.get(3),
+            created: row.get(4),
+            updated: row.get(5),
         }
     }
 }
 
-#[derive(Debug)]
-enum SqlParameter {
-    Int(i32),
-    BigInt(i64),
-    Float(f64),
-    Varchar(String),
+#[derive(Serialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+enum SetResult {
+    Created { rank: StoryRank },
+    Updated { rank: StoryRank },
+    /// Not written, because of the field at the given path
+    Invalid { field: String, reason: String },
 }
 
-impl From<i64> for SqlParameter { fn from(i: i64) -> Self { SqlParameter::BigInt(i) } }
+async fn do_set_story_ranking(auth: &mut AuthenticatedConnection, model: &Vec<SetStory>, mode: SetMode,
+                              metrics: &Metrics) -> Result<String, WebError> {
+    let txn = auth.conn.transaction().await?;
+    let mut results = Vec::with_capacity(model.len());
+    for (i, set) in model.iter().enumerate() {
+        if let Err((field, err)) = set.is_valid() {
+            if mode == SetMode::AllOrNothing {
+                return Err(WebError::Validation(format!("[{}].{}", i, field), err));
+            }
 
-impl From<i32> for SqlParameter { fn from(i: i32) -> Self { SqlParameter::Int(i) } }
+            results.push(SetResult::Invalid { field: format!("[{}].{}", i, field), reason: err });
+            continue;
+        }
 
-impl From<f64> for SqlParameter { fn from(f: f64) -> Self { SqlParameter::Float(f) } }
+        // stories are only ranked once synced, so an unknown ID inserts nothing instead of failing the foreign key
+        let sql = format!("
+                    insert into hnstar.story_user_rank as r (user_main_id, story_id, stars, flags, comment, created, updated)
+                    select $1, story_id, coalesce($3, 0), coalesce($4, 0), coalesce($5, ''), now(), now()
+                    from hnstar.story
+                    where story_id = $2
+                    on conflict (user_main_id, story_id)
+                    do update set
+                        stars = coalesce($3, r.stars),
+                        flags = coalesce($4, r.flags),
+                        comment = coalesce($5, r.comment),
+                        updated = now()
+                    returning {}, r.xmax = 0", RANK_COLUMNS);
+        let row = txn.query_opt(sql.as_str(), &[&auth.user.user_id, &set.story_id, &set.stars, &set.flags, &set.comment])
+            .instrument(tracing::info_span!("query", story_id = set.story_id))
+            .await?;
+        let row = match row {
+            Some(row) => row,
+            None if mode == SetMode::AllOrNothing => {
+                return Err(WebError::Validation(format!("[{}].story_id", i), format!("No story {}", set.story_id)));
+            }
+            None => {
+                results.push(SetResult::Invalid {
+                    field: format!("[{}].story_id", i),
+                    reason: format!("No story {}", set.story_id),
+                });
+                continue;
+            }
+        };
 
-impl From<String> for SqlParameter { fn from(s: String) -> Self { SqlParameter::Varchar(s) } }
+        let rank = StoryRank::from(&row);
+        results.push(if row.get(6) { SetResult::Created { rank } } else { SetResult::Updated { rank } });
+    }
 
-impl SqlParameter {
-    fn to_dynamic(&self) -> &(dyn ToSql + Sync) {
-        match self {
-            SqlParameter::Int(i) => i as &(dyn ToSql + Sync),
-            SqlParameter::BigInt(i) => i as &(dyn ToSql + Sync),
-            SqlParameter::Float(f) => f as &(dyn ToSql + Sync),
-            SqlParameter::Varchar(v) => v as &(dyn ToSql + Sync),
+    txn.commit().await?;
+    for (set, result) in model.iter().zip(results.iter()) {
+        if let SetResult::Invalid { .. } = result {
+            continue;
         }
+        if set.stars.is_some() { metrics.record_rank_written("/ranks/set", "stars", 1); }
+        if set.flags.is_some() { metrics.record_rank_written("/ranks/set", "flags", 1); }
+        if set.comment.is_some() { metrics.record_rank_written("/ranks/set", "comment", 1); }
     }
+
+    Ok(serde_json::to_string(&results)?)
 }
 
-struct QueryParameters {
-    query: String,
-    parameters: Vec<SqlParameter>,
+/// Stories to mark read or unread: by ID, or every story a query matches, regardless of its paging
+#[derive(Deserialize)]
+struct MarkRead {
+    #[serde(rename = "storyIds")]
+    story_ids: Option<Vec<i64>>,
+    query: Option<StoryRankingFilter>,
+    /// Mark the stories unread instead when false
+    read: Option<bool>,
 }
 
-fn get_query<'a>(model: &StoryRankingFilter, user_id: i32) -> Result<QueryParameters, WebError> {
-    let default = BigIntFilter {
-        gt: Some((chrono::Utc::now() + Duration::days(-10)).timestamp()),
-        lt: None,
+/// Most story IDs a bulk request takes
+const MAX_BATCH_IDS: usize = 1000;
+
+/// The stories a bulk request changed; unknown IDs are left out
+#[derive(Serialize)]
+struct MarkedStories {
+    #[serde(rename = "storyIds")]
+    story_ids: Vec<i64>,
+}
+
+async fn do_mark_read(auth: &mut AuthenticatedConnection, model: &MarkRead, data: &AppState) -> Result<String, WebError> {
+    // the stories to mark, as a query of their IDs using the user as $1
+    let (stories, parameters) = match (&model.story_ids, &model.query) {
+        (Some(story_ids), None) => {
+            if story_ids.len() > MAX_BATCH_IDS {
+                return Err(WebError::Validation(
+                    String::from("storyIds"), format!("At most {} stories can be marked at once", MAX_BATCH_IDS)));
+            }
+
+            let mut parameters = Parameters::new();
+            parameters.bind(auth.user.user_id);
+            let story_ids = parameters.bind(story_ids.clone());
+            (format!("select story_id from hnstar.story where story_id = any({})", story_ids), parameters.into_vec())
+        }
+        (None, Some(query)) => {
+            let mut matched = get_query(query, auth.user.user_id, &data.domains)?;
+            matched.parameters.truncate(matched.count_parameters);
+            (matched.ids_query, matched.parameters)
+        }
+        _ => {
+            return Err(WebError::Validation(String::from("storyIds"), String::from("Specify either storyIds or query")));
+        }
     };
-    let ts = model.timestamp
-        .map_or(default, |v| v);
 
-    if ts.gt.is_none() && ts.lt.is_none() {
-        return Err(WebError::Invalid(String::from("Must specify timestamp filter")));
-    }
+    // marking stories read is a visit, which the new since last visit filter starts from
+    let sql = if model.read.unwrap_or(true) {
+        format!("
+            with marked as (
+                insert into hnstar.story_user_rank (user_main_id, story_id, stars, flags, comment, created, updated)
+                select $1, story_id, 0, {flag}, '', now(), now()
+                from ({stories}) marked
+                on conflict (user_main_id, story_id)
+                do update set
+                    flags = coalesce(hnstar.story_user_rank.flags, 0) | {flag},
+                    updated = now()
+                returning story_id
+            ), visit as (
+                insert into hnstar.user_last_read (user_main_id, read)
+                select $1, now()
+                where exists (select 1 from marked)
+                on conflict (user_main_id) do update set read = excluded.read
+            )
+            select story_id from marked", flag = flags::READ_FLAG, stories = stories)
+    } else {
+        format!("
+            update hnstar.story_user_rank
+            set flags = coalesce(flags, 0) & ~{flag}, updated = now()
+            where user_main_id = $1 and story_id in ({stories})
+            returning story_id", flag = flags::READ_FLAG, stories = stories)
+    };
 
-    // from
-    let from_clause = String::from("
-        with stats as (
-            select avg(score) mean_score, stddev(score) stddev_score
-            from hnstar.story
-            where timestamp > $2
-        ), scored_stories as (
-            select * from hnstar.story, stats
-        )
-        select s.story_id, s.score, s.timestamp, s.title, s.url
-            , s.status, s.descendants, r.stars, r.flags
-            , cast(count(*) over() as integer) as full_count
-        from scored_stories s
-        left join hnstar.story_user_rank r
-            on r.story_id = s.story_id and r.user_main_id = $1
-    ");
+    let rows = run_query(&mut auth.conn, &sql, &parameters, data.limits).await?;
+    let story_ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+    data.metrics.record_rank_written("/ranks/read", "read", story_ids.len());
+
+    Ok(serde_json::to_string(&MarkedStories { story_ids })?)
+}
 
-    // where
-    let mut parameters: Vec<SqlParameter> = vec![SqlParameter::Int(user_id)];
-    let mut where_query: Vec<String> = vec![String::from("")];
+#[post("/read")]
+async fn mark_read(req: HttpRequest, data: web::Data<AppState>, model: web::Json<MarkRead>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
 
-    if let Some(gt_ts) = ts.gt {
-        parameters.push(SqlParameter::from(gt_ts));
-        where_query.push(format!("timestamp > ${}", parameters.len()));
+    match do_mark_read(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
     }
+}
+
+/// Stories to hide from queries until a time, given in seconds since the epoch, or to unsnooze when it is null
+#[derive(Deserialize)]
+struct Snooze {
+    #[serde(rename = "storyIds")]
+    story_ids: Vec<i64>,
+    until: Option<i64>,
+}
 
-    if let Some(lt_ts) = ts.lt {
-        parameters.push(SqlParameter::from(lt_ts));
-        where_query.push(format!("timestamp < ${}", parameters.len()));
+async fn do_snooze(auth: &mut AuthenticatedConnection, model: &Snooze, data: &AppState) -> Result<String, WebError> {
+    if model.story_ids.len() > MAX_BATCH_IDS {
+        return Err(WebError::Validation(
+            String::from("storyIds"), format!("At most {} stories can be snoozed at once", MAX_BATCH_IDS)));
     }
 
-    // Combine title, comment, and url filters with OR instead of AND
-    if model.title.is_some() || model.comment.is_some() || model.url.is_some() {
-        let mut sub_where_query: Vec<String> = vec![String::from("")];
-
-        if let Some(title) = model.title.clone() {
-            parameters.push(SqlParameter::from(title.regex));
-            if title.not {
-                sub_where_query.push(format!("title !~* ${}", parameters.len()));
-            } else {
-                sub_where_query.push(format!("title ~* ${}", parameters.len()));
+    let mut parameters = Parameters::new();
+    let user = parameters.bind(auth.user.user_id);
+    let story_ids = parameters.bind(model.story_ids.clone());
+    let sql = match model.until {
+        Some(until) => {
+            if until <= Utc::now().timestamp() {
+                return Err(WebError::Validation(String::from("until"), String::from("Must be in the future")));
             }
-        }
 
-        if let Some(comment) = model.comment.clone() {
-            parameters.push(SqlParameter::from(comment.regex));
-            if comment.not {
-                sub_where_query.push(format!("comment !~* ${}", parameters.len()));
-            } else {
-                sub_where_query.push(format!("comment ~* ${}", parameters.len()));
-            }
+            format!("
+                insert into hnstar.story_user_rank (user_main_id, story_id, stars, flags, comment, created, updated, snoozed_until)
+                select {user}, story_id, 0, 0, '', now(), now(), to_timestamp(cast({until} as bigint))
+                from hnstar.story
+                where story_id = any({story_ids})
+                on conflict (user_main_id, story_id)
+                do update set snoozed_until = excluded.snoozed_until, updated = now()
+                returning story_id", user = user, story_ids = story_ids, until = parameters.bind(until))
         }
+        None => format!("
+            update hnstar.story_user_rank
+            set snoozed_until = null, updated = now()
+            where user_main_id = {} and story_id = any({})
+            returning story_id", user, story_ids),
+    };
 
-        if let Some(url) = model.url.clone() {
-            parameters.push(SqlParameter::from(url.regex));
-            if url.not {
-                sub_where_query.push(format!("url !~* ${}", parameters.len()));
-            } else {
-                sub_where_query.push(format!("url ~* ${}", parameters.len()));
-            }
-        }
+    let rows = run_query(&mut auth.conn, &sql, &parameters.into_vec(), data.limits).await?;
+    let story_ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+    data.metrics.record_rank_written("/ranks/snooze", "snoozed_until", story_ids.len());
 
-        sub_where_query.retain(|v| !v.is_empty());
-        let sub_where_clause = format!("({})", sub_where_query.join(" or "));
-        where_query.push(sub_where_clause);
-    }
+    Ok(serde_json::to_string(&MarkedStories { story_ids })?)
+}
 
-    if let Some(score) = &model.score {
-        if let Some(gt_score) = score.gt {
-            parameters.push(SqlParameter::from(gt_score));
-            where_query.push(format!("score > ${}", parameters.len()));
-        }
+#[post("/snooze")]
+async fn snooze(req: HttpRequest, data: web::Data<AppState>, model: web::Json<Snooze>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
 
-        if let Some(lt_score) = score.lt {
-            parameters.push(SqlParameter::from(lt_score));
-            where_query.push(format!("score < ${}", parameters.len()));
-        }
+    match do_snooze(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
     }
+}
 
-    // z_score with mean and stddev over timestamp range
-    if let Some(z_score) = &model.z_score {
-        if let Some(gt_z_score) = z_score.gt {
-            parameters.push(SqlParameter::from(gt_z_score));
-            where_query.push(format!("((cast(s.score as float) - s.mean_score) / s.stddev_score) >= ${}", parameters.len()));
-        }
+/// Ranking fields `/ranks/clear` can reset, by their name in requests, with their column and the value a new ranking
+/// has in it
+const CLEARABLE_FIELDS: &[(&str, &str, &str)] = &[
+    ("stars", "stars", "0"),
+    ("flags", "flags", "0"),
+    ("comment", "comment", "''"),
+    ("snoozedUntil", "snoozed_until", "null"),
+];
 
-        if let Some(lt_z_score) = z_score.lt {
-            parameters.push(SqlParameter::from(lt_z_score));
-            where_query.push(format!("((cast(s.score as float) - s.mean_score) / s.stddev_score) >= ${}", parameters.len()));
-        }
-    }
+/// Fields of the user's rankings of stories to clear
+#[derive(Deserialize)]
+struct ClearRanks {
+    #[serde(rename = "storyIds")]
+    story_ids: Vec<i64>,
+    fields: Vec<String>,
+}
 
-    if let Some(stars) = model.stars {
-        if let Some(gt_stars) = stars.gt {
-            parameters.push(SqlParameter::from(gt_stars));
-            where_query.push(format!("stars > ${}", parameters.len()));
-        }
+/// Stories whose rankings to delete
+#[derive(Deserialize)]
+struct DeleteRanks {
+    #[serde(rename = "storyIds")]
+    story_ids: Vec<i64>,
+}
 
-        if let Some(lt_stars) = stars.lt {
-            parameters.push(SqlParameter::from(lt_stars));
-            where_query.push(format!("stars < ${}", parameters.len()));
+async fn do_clear_ranks(auth: &mut AuthenticatedConnection, model: &ClearRanks, data: &AppState) -> Result<String, WebError> {
+    if model.story_ids.len() > MAX_BATCH_IDS {
+        return Err(WebError::Validation(
+            String::from("storyIds"), format!("At most {} stories can be cleared at once", MAX_BATCH_IDS)));
+    }
+    if model.fields.is_empty() {
+        return Err(WebError::Validation(String::from("fields"), String::from("Specify at least one field")));
+    }
+
+    let mut columns = vec![];
+    for (i, field) in model.fields.iter().enumerate() {
+        let (_, column, default) = CLEARABLE_FIELDS.iter().find(|(name, _, _)| name == field)
+            .ok_or_else(|| WebError::Validation(
+                format!("fields[{}]", i),
+                format!("Unknown field '{}', expected one of {}", field,
+                        CLEARABLE_FIELDS.iter().map(|(name, _, _)| *name).collect::<Vec<_>>().join(", "))))?;
+        if !columns.contains(&(*column, *default)) {
+            columns.push((*column, *default));
         }
     }
 
-    if let Some(status) = model.status {
-        parameters.push(SqlParameter::from(status));
-        where_query.push(format!("status = ${}", parameters.len()));
+    let mut parameters = Parameters::new();
+    let user = parameters.bind(auth.user.user_id);
+    let story_ids = parameters.bind(model.story_ids.clone());
+    let assignments: Vec<String> = columns.iter().map(|(column, default)| format!("{} = {}", column, default)).collect();
+    let sql = format!("
+        update hnstar.story_user_rank
+        set {}, updated = now()
+        where user_main_id = {} and story_id = any({})
+        returning story_id", assignments.join(", "), user, story_ids);
+
+    let rows = run_query(&mut auth.conn, &sql, &parameters.into_vec(), data.limits).await?;
+    let story_ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+    for (column, _) in &columns {
+        data.metrics.record_rank_written("/ranks/clear", column, story_ids.len());
     }
 
-    if let Some(flags) = model.flags {
-        parameters.push(SqlParameter::from(flags));
-        where_query.push(format!("flags = ${}", parameters.len()));
+    Ok(serde_json::to_string(&MarkedStories { story_ids })?)
+}
+
+async fn do_delete_ranks(auth: &mut AuthenticatedConnection, model: &DeleteRanks, data: &AppState) -> Result<String, WebError> {
+    if model.story_ids.len() > MAX_BATCH_IDS {
+        return Err(WebError::Validation(
+            String::from("storyIds"), format!("At most {} rankings can be deleted at once", MAX_BATCH_IDS)));
     }
 
-    where_query.retain(|v| !v.is_empty());
-    let where_clause = format!("where {} ", where_query.join(" and "));
+    let mut parameters = Parameters::new();
+    let user = parameters.bind(auth.user.user_id);
+    let story_ids = parameters.bind(model.story_ids.clone());
+    let sql = format!("
+        delete from hnstar.story_user_rank
+        where user_main_id = {} and story_id = any({})
+        returning story_id", user, story_ids);
 
-    // sorting
-    let allowed_sorts = vec!["timestamp", "score", "stars"];
-    let mut sort_query: Vec<String> = vec![];
-    let default = vec![StoryRankingSort {
-        sort: String::from("timestamp"),
-        asc: false,
-    }];
-    let sorts = model.sort.as_ref().map_or(&default, |v| v);
-    for sort in sorts.iter() {
-        if allowed_sorts.contains(&sort.sort.as_str()) {
-            if sort.asc {
-                sort_query.push(format!("{} asc", sort.sort));
-            } else {
-                sort_query.push(format!("{} desc", sort.sort));
-            }
-        }
+    let rows = run_query(&mut auth.conn, &sql, &parameters.into_vec(), data.limits).await?;
+    let story_ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+    data.metrics.record_rank_written("/ranks/delete", "ranking", story_ids.len());
+
+    Ok(serde_json::to_string(&MarkedStories { story_ids })?)
+}
+
+#[post("/clear")]
+async fn clear_ranks(req: HttpRequest, data: web::Data<AppState>, model: web::Json<ClearRanks>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_clear_ranks(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
     }
+}
+
+#[post("/delete")]
+async fn delete_ranks(req: HttpRequest, data: web::Data<AppState>, model: web::Json<DeleteRanks>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
 
-    if sort_query.is_empty() {
-        sort_query.push(String::from("timestamp desc"));
+    match do_delete_ranks(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
     }
+}
 
-    let page_size = clamp(model.page_size.map_or(100, |v| v), 1, 500);
-    sort_query.push(String::from(format!("limit {}", page_size)));
-    let page_number = clamp_min(model.page_number.map_or(0, |v| v), 0);
-    sort_query.push(String::from(format!("offset {}", page_number * page_size)));
+/// Most changes listed or undone in one request
+const MAX_RANK_CHANGES: i64 = 100;
 
-    let sort_clause = format!("order by {} ", sort_query.join(" "));
+/// A page of the user's ranking changes, newest first, continuing before a log ID
+#[derive(Deserialize)]
+struct RankLogFilter {
+    limit: Option<i64>,
+    #[serde(rename = "beforeLogId")]
+    before_log_id: Option<i64>,
+}
 
-    let query = format!("{} \n{} \n{}", from_clause, where_clause, sort_clause);
-    #[cfg(feature = "debug")]
-        {
-            println!("{}", &query);
-            println!("{:?}", parameters);
-        }
-    Ok(QueryParameters { query, parameters })
+/// One change to a ranking, with the stored row before and after it: no before when it was created, no after when
+/// it was deleted
+#[derive(Serialize)]
+struct RankChange {
+    #[serde(rename = "logId")]
+    log_id: i64,
+    #[serde(rename = "storyId")]
+    story_id: i64,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+    /// The change this one undid
+    undoes: Option<i64>,
+    /// Whether this change has been undone
+    undone: bool,
+    #[serde(with = "json_time")]
+    created: NaiveDateTime,
 }
 
-fn clamp_min(i: i32, min: i32) -> i32 {
-    if i < min { min } else { i }
+/// The number of most recent changes to undo
+#[derive(Deserialize)]
+struct UndoRanks {
+    count: Option<i64>,
 }
 
-fn clamp(i: i32, min: i32, max: i32) -> i32 {
-    if i > max {
-        max
-    } else if i < min {
-        min
-    } else { i }
+/// An undone change and the ranking it restored, or none when undoing removed the ranking
+#[derive(Serialize)]
+struct UndoneChange {
+    #[serde(rename = "logId")]
+    log_id: i64,
+    #[serde(rename = "storyId")]
+    story_id: i64,
+    rank: Option<StoryRank>,
 }
 
-async fn do_get_story_ranking(auth: &mut AuthenticatedConnection, user_id: i32, model: &StoryRankingFilter) -> Result<String, WebError> {
-    let query = get_query(model, user_id)?;
-    let prep = auth.conn.prepare(&query.query).await?;
-    let rows: Vec<tokio_postgres::row::Row> = auth.conn.query(
-        &prep,
-        &query.parameters.iter()
-            .map(|v| v.to_dynamic())
-            .collect::<Vec<_>>()).await?;
-    let stories: Vec<GetStory> = rows.iter().map(GetStory::from).collect();
-    Ok(serde_json::to_string(&stories)?)
+fn parse_logged_row(row: Option<String>) -> Result<Option<serde_json::Value>, WebError> {
+    Ok(match row {
+        Some(row) => Some(serde_json::from_str(&row)?),
+        None => None,
+    })
 }
 
-#[post("/query")]
-async fn get_story_ranking(req: HttpRequest, data: web::Data<AppState>, model: web::Json<StoryRankingFilter>) -> impl Responder {
+async fn do_get_rank_log(auth: &mut AuthenticatedConnection, model: &RankLogFilter, data: &AppState) -> Result<String, WebError> {
+    let mut parameters = Parameters::new();
+    let mut query = Select::from("hnstar.story_user_rank_log l");
+    query.column("l.log_id, l.story_id, cast(l.before - 'user_main_id' as text), cast(l.after - 'user_main_id' as text)")
+        .column("l.undoes, exists (select 1 from hnstar.story_user_rank_log u where u.undoes = l.log_id), l.created")
+        .filter(Predicate::compare("l.user_main_id", Comparison::Eq, parameters.bind(auth.user.user_id)))
+        .order_by("l.log_id", false)
+        .limit(model.limit.unwrap_or(MAX_RANK_CHANGES).clamp(1, MAX_RANK_CHANGES));
+    if let Some(before) = model.before_log_id {
+        query.filter(Predicate::compare("l.log_id", Comparison::Lt, parameters.bind(before)));
+    }
+
+    let rows = run_query(&mut auth.conn, &query.to_sql(), &parameters.into_vec(), data.limits).await?;
+    let changes = rows.iter()
+        .map(|row| Ok(RankChange {
+            log_id: row.get(0),
+            story_id: row.get(1),
+            before: parse_logged_row(row.get(2))?,
+            after: parse_logged_row(row.get(3))?,
+            undoes: row.get(4),
+            undone: row.get(5),
+            created: row.get(6),
+        }))
+        .collect::<Result<Vec<_>, WebError>>()?;
+    Ok(serde_json::to_string(&changes)?)
+}
+
+/// Restores the rankings from before the user's most recent changes that are not undos or undone already, newest
+/// first. Every row a request wrote is a change, so undoing a bulk request takes as many as it changed.
+async fn do_undo_ranks(auth: &mut AuthenticatedConnection, model: &UndoRanks, data: &AppState) -> Result<String, WebError> {
+    let count = model.count.unwrap_or(1);
+    if !(1..=MAX_RANK_CHANGES).contains(&count) {
+        return Err(WebError::Validation(
+            String::from("count"), format!("Must be between 1 and {}", MAX_RANK_CHANGES)));
+    }
+
+    let txn = auth.conn.transaction().await?;
+    // one undo at a time per user, so concurrent requests don't undo the same change
+    txn.execute("select pg_advisory_xact_lock(cast($1 as integer))", &[&auth.user.user_id]).await?;
+    let changes = txn.query("
+        select l.log_id, l.story_id, cast(l.before as text)
+        from hnstar.story_user_rank_log l
+        where l.user_main_id = $1 and l.undoes is null
+            and not exists (select 1 from hnstar.story_user_rank_log u where u.undoes = l.log_id)
+        order by l.log_id desc
+        limit $2", &[&auth.user.user_id, &count]).await?;
+
+    let restore = format!("
+        insert into hnstar.story_user_rank as r
+            (user_main_id, story_id, stars, flags, comment, created, updated, snoozed_until)
+        select $1, $2, b.stars, b.flags, b.comment, b.created, b.updated, b.snoozed_until
+        from jsonb_populate_record(null::hnstar.story_user_rank, cast(cast($3 as text) as jsonb)) b
+        on conflict (user_main_id, story_id)
+        do update set
+            stars = excluded.stars,
+            flags = excluded.flags,
+            comment = excluded.comment,
+            created = excluded.created,
+            updated = excluded.updated,
+            snoozed_until = excluded.snoozed_until
+        returning {}", RANK_COLUMNS);
+    let mut undone = Vec::with_capacity(changes.len());
+    for change in &changes {
+        let log_id: i64 = change.get(0);
+        let story_id: i64 = change.get(1);
+        let before: Option<String> = change.get(2);
+
+        // the log trigger records which change the restore undoes
+        txn.execute("select set_config('hnstar.undoes', $1, true)", &[&log_id.to_string()]).await?;
+        let rank = match before {
+            Some(before) => {
+                let row = txn.query_one(restore.as_str(), &[&auth.user.user_id, &story_id, &before]).await?;
+                Some(StoryRank::from(&row))
+            }
+            None => {
+                txn.execute("delete from hnstar.story_user_rank where user_main_id = $1 and story_id = $2",
+                            &[&auth.user.user_id, &story_id]).await?;
+                None
+            }
+        };
+        undone.push(UndoneChange { log_id, story_id, rank });
+    }
+    txn.execute("select set_config('hnstar.undoes', '', true)", &[]).await?;
+
+    txn.commit().await?;
+    data.metrics.record_rank_written("/ranks/undo", "ranking", undone.len());
+
+    Ok(serde_json::to_string(&undone)?)
+}
+
+/// The user's changes to rankings, newest first
+#[post("/log")]
+async fn get_rank_log(req: HttpRequest, data: web::Data<AppState>, model: web::Json<RankLogFilter>) -> impl Responder {
     let mut auth = match data.authenticate(&req, &data).await {
         Ok(auth) => auth,
-        Err(_) => {
-            let conn = data.conn().await;
-            match conn {
-                Ok(conn) => AuthenticatedConnection {
-                    conn,
-                    user: UserData {
-                        status: 0,
-                        user_id: -1,
-                        username: String::from("default"),
-                        email: None,
-                        name: None,
-                        created: chrono::Utc::now().naive_utc(),
-                        updated: chrono::Utc::now().naive_utc(),
-                    },
-                },
-                Err(err) => {
-                    println!("{:?}", err);
-                    return err.to_response();
-                }
-            }
-        }
+        Err(err) => { return err.to_response(&req); }
     };
 
-    let user_id = auth.user.user_id;
-    let result = do_get_story_ranking(&mut auth, user_id, &model).await;
-    match result {
-        Ok(json) => HttpResponse::Ok().body(json),
-        Err(err) => err.to_response()
+    match do_get_rank_log(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let addr_port = match std::env::var("BIND_ADDR_PORT") {
-        Ok(bind) => bind.to_socket_addrs().unwrap().next().unwrap(),
-        Err(_) => {
-            println!("No BIND_ADDR_PORT environment variable, defaulting to localhost:8000");
-            String::from("127.0.0.1:8000").to_socket_addrs().unwrap().next().unwrap()
-        }
+#[post("/undo")]
+async fn undo_ranks(req: HttpRequest, data: web::Data<AppState>, model: web::Json<UndoRanks>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
     };
 
-    let secure = if std::env::var("PRIVATE_KEY_FILE").is_ok() ||
-        std::env::var("CERTIFICATE_FILE").is_ok() {
-        use std::path::{Path};
-        let private_key_file = std::env::var("PRIVATE_KEY_FILE")
+    match do_undo_ranks(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+/// Longest tag name
+const MAX_TAG_LENGTH: usize = 40;
+
+/// A tag name as stored: trimmed and lowercased
+fn normalize_tag(field: &str, name: &str) -> Result<String, WebError> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() || name.chars().count() > MAX_TAG_LENGTH {
+        return Err(WebError::Validation(
+            String::from(field), format!("Tags must have between 1 and {} characters", MAX_TAG_LENGTH)));
+    }
+
+    Ok(name)
+}
+
+fn normalize_tags(field: &str, names: &[String]) -> Result<Vec<String>, WebError> {
+    names.iter().enumerate().map(|(i, name)| normalize_tag(&format!("{}[{}]", field, i), name)).collect()
+}
+
+/// Stories with any of the included tags and none of the excluded ones
+#[derive(Deserialize, Serialize, Clone)]
+struct TagFilter {
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+}
+
+/// Stories the user tagged with one of the tags in the parameter
+fn tag_predicate(user: Placeholder, tags: Placeholder) -> Predicate {
+    Predicate::sql(format!("exists (
+            select 1 from hnstar.story_tag st join hnstar.tag t on t.tag_id = st.tag_id
+            where st.story_id = s.story_id and t.user_main_id = {} and t.name = any({}))", user, tags))
+}
+
+/// Tags to add to or remove from stories
+#[derive(Deserialize)]
+struct TagStories {
+    #[serde(rename = "storyIds")]
+    story_ids: Vec<i64>,
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct TagStats {
+    name: String,
+    stories: i64,
+}
+
+async fn do_tag_stories(auth: &mut AuthenticatedConnection, model: &TagStories, add: bool,
+                        data: &AppState) -> Result<String, WebError> {
+    if model.story_ids.len() > MAX_BATCH_IDS {
+        return Err(WebError::Validation(
+            String::from("storyIds"), format!("At most {} stories can be tagged at once", MAX_BATCH_IDS)));
+    }
+
+    let mut parameters = Parameters::new();
+    let user = parameters.bind(auth.user.user_id);
+    let story_ids = parameters.bind(model.story_ids.clone());
+    let tags = parameters.bind(normalize_tags("tags", &model.tags)?);
+    // tags created by the statement are not visible to its other reads, so they are taken from the insert
+    let sql = if add {
+        format!("
+            with created_tags as (
+                insert into hnstar.tag (user_main_id, name)
+                select {user}, unnest(cast({tags} as text[]))
+                on conflict (user_main_id, name) do nothing
+                returning tag_id
+            ), tags as (
+                select tag_id from created_tags
+                union
+                select tag_id from hnstar.tag where user_main_id = {user} and name = any({tags})
+            )
+            insert into hnstar.story_tag (story_id, tag_id)
+            select story.story_id, tags.tag_id
+            from hnstar.story story, tags
+            where story.story_id = any({story_ids})
+            on conflict do nothing
+            returning story_id", user = user, tags = tags, story_ids = story_ids)
+    } else {
+        format!("
+            delete from hnstar.story_tag st
+            using hnstar.tag t
+            where t.tag_id = st.tag_id and t.user_main_id = {} and t.name = any({}) and st.story_id = any({})
+            returning st.story_id", user, tags, story_ids)
+    };
+
+    let rows = run_query(&mut auth.conn, &sql, &parameters.into_vec(), data.limits).await?;
+    let mut story_ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+    story_ids.sort_unstable();
+    story_ids.dedup();
+    data.metrics.record_rank_written(if add { "/tags/add" } else { "/tags/remove" }, "tags", story_ids.len());
+
+    Ok(serde_json::to_string(&MarkedStories { story_ids })?)
+}
+
+#[post("/add")]
+async fn add_tags(req: HttpRequest, data: web::Data<AppState>, model: web::Json<TagStories>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_tag_stories(&mut auth, &model, true, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[post("/remove")]
+async fn remove_tags(req: HttpRequest, data: web::Data<AppState>, model: web::Json<TagStories>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_tag_stories(&mut auth, &model, false, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+/// The user's tags with how many stories each is on, including tags left on none
+async fn do_get_tags(auth: &mut AuthenticatedConnection, data: &AppState) -> Result<Vec<TagStats>, WebError> {
+    let mut parameters = Parameters::new();
+    let mut query = Select::from("hnstar.tag t");
+    query.column("t.name, count(st.story_id)")
+        .join(Join::Left(String::from("hnstar.story_tag st"), vec![Predicate::sql("st.tag_id = t.tag_id")]))
+        .filter(Predicate::compare("t.user_main_id", Comparison::Eq, parameters.bind(auth.user.user_id)))
+        .group_by("t.name")
+        .order_by("t.name", true);
+
+    let rows = run_query(&mut auth.conn, &query.to_sql(), &parameters.into_vec(), data.limits).await?;
+    Ok(rows.iter().map(|row| TagStats { name: row.get(0), stories: row.get(1) }).collect())
+}
+
+#[get("/list")]
+async fn get_tags(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_get_tags(&mut auth, &data).await {
+        Ok(tags) => HttpResponse::Ok().json(tags),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+/// Rename a tag, which fails when the new name is taken; merge into that tag instead
+#[derive(Deserialize)]
+struct RenameTag {
+    from: String,
+    to: String,
+}
+
+/// Move the stories of the `from` tags to `into`, created when missing, and delete the `from` tags
+#[derive(Deserialize)]
+struct MergeTags {
+    from: Vec<String>,
+    into: String,
+}
+
+async fn do_rename_tag(auth: &mut AuthenticatedConnection, model: &RenameTag, data: &AppState) -> Result<String, WebError> {
+    let from = normalize_tag("from", &model.from)?;
+    let to = normalize_tag("to", &model.to)?;
+    let txn = auth.conn.transaction().await?;
+    let existing = txn.query_opt(
+        "select tag_id from hnstar.tag where user_main_id = $1 and name = $2", &[&auth.user.user_id, &to]).await?;
+    if existing.is_some() && from != to {
+        return Err(WebError::Validation(String::from("to"), format!("Tag '{}' already exists, merge into it instead", to)));
+    }
+
+    let renamed = txn.execute(
+        "update hnstar.tag set name = $3 where user_main_id = $1 and name = $2",
+        &[&auth.user.user_id, &from, &to]).await?;
+    if renamed == 0 {
+        return Err(WebError::Validation(String::from("from"), format!("No tag '{}'", from)));
+    }
+
+    txn.commit().await?;
+    tag_stats(auth, &to, data).await
+}
+
+async fn do_merge_tags(auth: &mut AuthenticatedConnection, model: &MergeTags, data: &AppState) -> Result<String, WebError> {
+    let from = normalize_tags("from", &model.from)?;
+    let into = normalize_tag("into", &model.into)?;
+    let txn = auth.conn.transaction().await?;
+    txn.execute("
+        insert into hnstar.tag (user_main_id, name) values ($1, $2)
+        on conflict (user_main_id, name) do nothing", &[&auth.user.user_id, &into]).await?;
+    txn.execute("
+        insert into hnstar.story_tag (story_id, tag_id, created)
+        select st.story_id, target.tag_id, st.created
+        from hnstar.story_tag st
+        join hnstar.tag source on source.tag_id = st.tag_id
+        join hnstar.tag target on target.user_main_id = source.user_main_id and target.name = $3
+        where source.user_main_id = $1 and source.name = any($2) and source.name <> $3
+        on conflict do nothing", &[&auth.user.user_id, &from, &into]).await?;
+    txn.execute(
+        "delete from hnstar.tag where user_main_id = $1 and name = any($2) and name <> $3",
+        &[&auth.user.user_id, &from, &into]).await?;
+    txn.commit().await?;
+    tag_stats(auth, &into, data).await
+}
+
+/// The tag with its story count, as the result of changing it
+async fn tag_stats(auth: &mut AuthenticatedConnection, name: &str, data: &AppState) -> Result<String, WebError> {
+    let tag = do_get_tags(auth, data).await?.into_iter().find(|t| t.name == name);
+    Ok(serde_json::to_string(&tag)?)
+}
+
+#[post("/rename")]
+async fn rename_tag(req: HttpRequest, data: web::Data<AppState>, model: web::Json<RenameTag>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_rename_tag(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[post("/merge")]
+async fn merge_tags(req: HttpRequest, data: web::Data<AppState>, model: web::Json<MergeTags>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_merge_tags(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+/// Longest note, in characters of Markdown
+const MAX_NOTE_LENGTH: usize = 10000;
+
+fn validate_note(markdown: &str) -> Result<(), WebError> {
+    if markdown.trim().is_empty() || markdown.chars().count() > MAX_NOTE_LENGTH {
+        return Err(WebError::Validation(
+            String::from("markdown"), format!("Notes must have between 1 and {} characters", MAX_NOTE_LENGTH)));
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Note {
+    #[serde(rename = "noteId")]
+    note_id: i32,
+    #[serde(rename = "storyId")]
+    story_id: i64,
+    markdown: String,
+    /// The Markdown rendered and sanitized
+    html: String,
+    #[serde(with = "json_time")]
+    created: NaiveDateTime,
+    #[serde(with = "json_time")]
+    updated: NaiveDateTime,
+    /// Number of earlier versions kept by edits
+    revisions: i64,
+}
+
+const NOTE_COLUMNS: &str = "n.note_id, n.story_id, n.body, n.created, n.updated
+    , (select count(*) from hnstar.story_note_revision v where v.note_id = n.note_id)";
+
+impl From<&tokio_postgres::row::Row> for Note {
+    fn from(row: &tokio_postgres::row::Row) -> Self {
+        let markdown: String = row.get(2);
+        Note {
+            note_id: row.get(0),
+            story_id: row.get(1),
+            html: markdown::to_safe_html(&markdown),
+            markdown,
+            created: row.get(3),
+            updated: row.get(4),
+            revisions: row.get(5),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct NoteRevision {
+    revision: i32,
+    markdown: String,
+    html: String,
+    /// When this version was written
+    #[serde(with = "json_time")]
+    created: NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+struct AddNote {
+    #[serde(rename = "storyId")]
+    story_id: i64,
+    markdown: String,
+}
+
+#[derive(Deserialize)]
+struct EditNote {
+    #[serde(rename = "noteId")]
+    note_id: i32,
+    markdown: String,
+}
+
+#[derive(Deserialize)]
+struct NoteRef {
+    #[serde(rename = "noteId")]
+    note_id: i32,
+}
+
+#[derive(Deserialize)]
+struct StoryNotes {
+    #[serde(rename = "storyId")]
+    story_id: i64,
+}
+
+fn note_not_found(note_id: i32) -> WebError {
+    WebError::Validation(String::from("noteId"), format!("No note {}", note_id))
+}
+
+async fn do_add_note(auth: &mut AuthenticatedConnection, model: &AddNote, data: &AppState) -> Result<String, WebError> {
+    validate_note(&model.markdown)?;
+    let sql = format!("
+        insert into hnstar.story_note as n (user_main_id, story_id, body)
+        select $1, story_id, $3 from hnstar.story where story_id = $2
+        returning {}", NOTE_COLUMNS);
+    let parameters = [auth.user.user_id.into(), model.story_id.into(), model.markdown.clone().into()];
+    let rows = run_query(&mut auth.conn, &sql, &parameters, data.limits).await?;
+    let note = rows.first().map(Note::from).ok_or_else(|| WebError::Validation(
+        String::from("storyId"), format!("No story {}", model.story_id)))?;
+    data.metrics.record_rank_written("/notes/add", "note", 1);
+    Ok(serde_json::to_string(&note)?)
+}
+
+/// Replace a note's text, keeping the previous text as a revision. The note is locked before its next revision is
+/// counted, so concurrent edits number their revisions one after the other.
+async fn do_edit_note(auth: &mut AuthenticatedConnection, model: &EditNote, data: &AppState) -> Result<String, WebError> {
+    validate_note(&model.markdown)?;
+    let note = [auth.user.user_id.into(), model.note_id.into()];
+    let edit = [auth.user.user_id.into(), model.note_id.into(), model.markdown.clone().into()];
+    let update = format!("
+        update hnstar.story_note n set body = $3, updated = now()
+        where note_id = $2 and user_main_id = $1
+        returning {}", NOTE_COLUMNS);
+    let mut results = run_queries(&mut auth.conn, &[
+        ("select note_id from hnstar.story_note where note_id = $2 and user_main_id = $1 for update", &note),
+        ("insert into hnstar.story_note_revision (note_id, revision, body, created)
+            select note_id, 1 + coalesce((
+                select max(revision) from hnstar.story_note_revision v where v.note_id = n.note_id), 0), body, updated
+            from hnstar.story_note n
+            where note_id = $2 and user_main_id = $1", &note),
+        (&update, &edit),
+    ], data.limits).await?;
+    let rows = results.pop().unwrap_or_default();
+    let note = rows.first().map(Note::from).ok_or_else(|| note_not_found(model.note_id))?;
+    data.metrics.record_rank_written("/notes/edit", "note", 1);
+    Ok(serde_json::to_string(&note)?)
+}
+
+async fn do_delete_note(auth: &mut AuthenticatedConnection, model: &NoteRef, data: &AppState) -> Result<String, WebError> {
+    let parameters = [auth.user.user_id.into(), model.note_id.into()];
+    let rows = run_query(&mut auth.conn,
+        "delete from hnstar.story_note where note_id = $2 and user_main_id = $1 returning note_id",
+        &parameters, data.limits).await?;
+    if rows.is_empty() {
+        return Err(note_not_found(model.note_id));
+    }
+
+    data.metrics.record_rank_written("/notes/delete", "note", 1);
+    Ok(serde_json::to_string(&model.note_id)?)
+}
+
+/// The user's notes on a story, oldest first
+async fn do_get_notes(auth: &mut AuthenticatedConnection, model: &StoryNotes, data: &AppState) -> Result<String, WebError> {
+    let mut parameters = Parameters::new();
+    let mut query = Select::from("hnstar.story_note n");
+    query.column(NOTE_COLUMNS)
+        .filter(Predicate::compare("n.user_main_id", Comparison::Eq, parameters.bind(auth.user.user_id)))
+        .filter(Predicate::compare("n.story_id", Comparison::Eq, parameters.bind(model.story_id)))
+        .order_by("n.created", true)
+        .order_by("n.note_id", true);
+
+    let rows = run_query(&mut auth.conn, &query.to_sql(), &parameters.into_vec(), data.limits).await?;
+    let notes: Vec<Note> = rows.iter().map(Note::from).collect();
+    Ok(serde_json::to_string(&notes)?)
+}
+
+/// Earlier versions of a note, newest first
+async fn do_get_note_history(auth: &mut AuthenticatedConnection, model: &NoteRef, data: &AppState) -> Result<String, WebError> {
+    let mut parameters = Parameters::new();
+    let mut query = Select::from("hnstar.story_note_revision v");
+    query.column("v.revision, v.body, v.created")
+        .join(Join::Cross(String::from("hnstar.story_note n")))
+        .filter(Predicate::sql("n.note_id = v.note_id"))
+        .filter(Predicate::compare("n.user_main_id", Comparison::Eq, parameters.bind(auth.user.user_id)))
+        .filter(Predicate::compare("n.note_id", Comparison::Eq, parameters.bind(model.note_id)))
+        .order_by("v.revision", false);
+
+    let rows = run_query(&mut auth.conn, &query.to_sql(), &parameters.into_vec(), data.limits).await?;
+    let revisions: Vec<NoteRevision> = rows.iter()
+        .map(|row| {
+            let markdown: String = row.get(1);
+            NoteRevision { revision: row.get(0), html: markdown::to_safe_html(&markdown), markdown, created: row.get(2) }
+        })
+        .collect();
+    Ok(serde_json::to_string(&revisions)?)
+}
+
+#[post("/add")]
+async fn add_note(req: HttpRequest, data: web::Data<AppState>, model: web::Json<AddNote>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_add_note(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[post("/edit")]
+async fn edit_note(req: HttpRequest, data: web::Data<AppState>, model: web::Json<EditNote>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_edit_note(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[post("/delete")]
+async fn delete_note(req: HttpRequest, data: web::Data<AppState>, model: web::Json<NoteRef>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_delete_note(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[post("/list")]
+async fn get_notes(req: HttpRequest, data: web::Data<AppState>, model: web::Json<StoryNotes>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_get_notes(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[post("/history")]
+async fn get_note_history(req: HttpRequest, data: web::Data<AppState>, model: web::Json<NoteRef>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_get_note_history(&mut auth, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+/// The named flag bits, for clients to set flags and build flag filters with
+#[get("/flags")]
+async fn get_flags() -> impl Responder {
+    HttpResponse::Ok().json(flags::FLAG_BITS)
+}
+
+/// Set stars, flags and comments of stories, replying with the result of every item in order. By default nothing is
+/// written when an item is invalid; `?mode=bestEffort` writes the valid ones.
+#[post("/set")]
+async fn set_story_ranking(req: HttpRequest, data: web::Data<AppState>, model: web::Json<Vec<SetStory>>,
+                           options: web::Query<SetOptions>) -> impl Responder {
+    let mut auth = match data.authenticate(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    let mode = options.mode.unwrap_or(SetMode::AllOrNothing);
+    let result = do_set_story_ranking(&mut auth, &model, mode, &data.metrics).await;
+    match result {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone)]
+struct IntFilter {
+    gt: Option<i32>,
+    lt: Option<i32>,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone)]
+struct BigIntFilter {
+    gt: Option<i64>,
+    lt: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone)]
+struct FloatFilter {
+    gt: Option<f64>,
+    lt: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoryRankingSort {
+    sort: String,
+    asc: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct PgRegex {
+    regex: String,
+    not: bool,
+}
+
+/// Sites to show or hide, matched against normalized domains; `github.com` also matches `github.com/user`
+#[derive(Deserialize, Serialize, Clone)]
+struct DomainFilter {
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+}
+
+/// A domain as typed by a user, e.g. `https://www.news.Example.co.uk/`, in the form stories store them
+fn normalize_domain(domains: &DomainNormalizer, domain: &str) -> String {
+    let domain = domain.trim();
+    if domain.contains("://") {
+        domains.normalize(domain)
+    } else {
+        domains.normalize(&format!("https://{}", domain))
+    }
+}
+
+/// Stories whose domain, or its site without the path, is one of the domains in the parameter
+fn domain_predicate(domains: Placeholder) -> Predicate {
+    Predicate::sql(format!("(s.domain = any({p}) or split_part(s.domain, '/', 1) = any({p}))", p = domains))
+}
+
+/// Stories with a note of the user matching the full-text search in the parameter, in websearch syntax
+fn notes_predicate(user: Placeholder, query: Placeholder) -> Predicate {
+    Predicate::sql(format!("exists (
+            select 1 from hnstar.story_note n
+            where n.story_id = s.story_id and n.user_main_id = {} and n.search @@ websearch_to_tsquery('english', {}))",
+        user, query))
+}
+
+/// Posters to show or hide, by exact username
+#[derive(Deserialize, Serialize, Clone)]
+struct AuthorFilter {
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct StoryRankingFilter {
+    timestamp: Option<BigIntFilter>,
+    #[serde(rename = "pageSize")]
+    page_size: Option<i32>,
+    #[serde(rename = "pageNumber")]
+    page_number: Option<i32>,
+    /// Continue after the last story of a previous page instead of using `pageNumber`
+    cursor: Option<String>,
+    /// Full-text search of titles, domains and notes, in websearch syntax: `"exact phrase" rust -java`
+    search: Option<String>,
+    /// Also count all matching stories, which costs a scan of the whole filtered set when exact
+    count: Option<CountMode>,
+    /// Filters in the query language, e.g. `title:rust score>200 -domain:medium.com`, parsed into `terms`
+    q: Option<String>,
+    /// Filters combined with and/or/not, AND'd with the other filters
+    terms: Option<FilterExpr>,
+    title: Option<PgRegex>,
+    url: Option<PgRegex>,
+    domain: Option<DomainFilter>,
+    by: Option<AuthorFilter>,
+    tags: Option<TagFilter>,
+    score: Option<IntFilter>,
+    #[serde(rename = "zScore")]
+    z_score: Option<FloatFilter>,
+    /// Whether z-scores are of scores or of their logarithms, which a few huge stories skew less
+    #[serde(rename = "zScoreScale")]
+    z_score_scale: Option<ScoreScale>,
+    /// Score percentile, 0-100, among the same stories as the z-score; `{"gt": 90}` is the top 10%
+    percentile: Option<FloatFilter>,
+    /// What z-scores and percentiles are relative to, the whole window by default
+    normalization: Option<Normalization>,
+    /// A status, or any of several: `{"in": [0, 1]}`
+    status: Option<StatusFilter>,
+    /// Flags equal to a number, or named flags: `{"any": ["saved"], "none": ["hidden", "read"]}`
+    flags: Option<FlagsFilter>,
+    stars: Option<IntFilter>,
+    comment: Option<PgRegex>,
+    /// Full-text search of the user's notes, in websearch syntax
+    notes: Option<String>,
+    /// Only stories with, or without, the read flag
+    read: Option<bool>,
+    /// Only unread stories posted since the user last marked stories read
+    #[serde(rename = "newSinceLastVisit")]
+    new_since_last_visit: Option<bool>,
+    /// Which snoozed stories to show; by default those still snoozed are left out
+    snoozed: Option<SnoozedFilter>,
+    sort: Option<Vec<StoryRankingSort>>,
+    /// Time the hot and velocity sorts age stories to and ages in `q` count from, defaulting to now; cursors keep it
+    /// for later pages
+    #[serde(rename = "asOf")]
+    as_of: Option<i64>,
+    /// Gravity of the hot sort, how quickly stories fall with age
+    gravity: Option<f64>,
+}
+
+impl StoryRankingFilter {
+    /// The filter as the query applies it: the default window and sort, and the page size clamped to 1-500
+    fn with_defaults(&self) -> StoryRankingFilter {
+        let mut effective = self.clone();
+        effective.timestamp = Some(self.timestamp.unwrap_or(BigIntFilter {
+            gt: Some((chrono::Utc::now() + Duration::days(-10)).timestamp()),
+            lt: None,
+        }));
+        effective.page_size = Some(clamp(self.page_size.unwrap_or(100), 1, 500));
+        effective.normalization = Some(self.normalization.unwrap_or(Normalization::Window));
+        effective.snoozed = Some(self.snoozed.unwrap_or(SnoozedFilter::Hide));
+        effective.z_score_scale = Some(self.z_score_scale.unwrap_or(ScoreScale::Linear));
+        effective.as_of = Some(self.as_of.unwrap_or_else(|| chrono::Utc::now().timestamp()));
+        effective.gravity = Some(self.gravity.filter(|g| g.is_finite() && *g >= 0.0).unwrap_or(DEFAULT_GRAVITY));
+        effective.page_number = match self.cursor {
+            Some(_) => None,
+            None => Some(clamp_min(self.page_number.unwrap_or(0), 0)),
+        };
+
+        let sorts: Vec<StoryRankingSort> = self.sort.iter().flatten()
+            .filter(|sort| SORT_KEYS.iter().any(|key| key.name == sort.sort))
+            .cloned()
+            .collect();
+        effective.sort = Some(if sorts.is_empty() {
+            vec![StoryRankingSort { sort: String::from("timestamp"), asc: false }]
+        } else {
+            sorts
+        });
+
+        effective
+    }
+
+    /// Names of the filters in use, e.g. "score,timestamp", to label query metrics
+    fn active_filters(&self) -> String {
+        let filters = [
+            ("by", self.by.is_some()),
+            ("comment", self.comment.is_some()),
+            ("domain", self.domain.is_some()),
+            ("flags", self.flags.is_some()),
+            ("newSinceLastVisit", self.new_since_last_visit.unwrap_or(false)),
+            ("notes", self.notes.is_some()),
+            ("percentile", self.percentile.is_some()),
+            ("q", self.q.is_some() || self.terms.is_some()),
+            ("read", self.read.is_some()),
+            ("score", self.score.is_some()),
+            ("search", self.search.is_some()),
+            ("snoozed", self.snoozed.is_some()),
+            ("stars", self.stars.is_some()),
+            ("tags", self.tags.is_some()),
+            ("status", self.status.is_some()),
+            ("timestamp", self.timestamp.is_some()),
+            ("title", self.title.is_some()),
+            ("url", self.url.is_some()),
+            ("zScore", self.z_score.is_some()),
+        ];
+        let active: Vec<&str> = filters.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect();
+        if active.is_empty() { String::from("none") } else { active.join(",") }
+    }
+}
+
+/// Which stories a story's z-score compares it with: those in the timestamp window, those posted the same UTC day,
+/// or those posted within 12 hours either side of it. Window and day means and deviations are combined from daily
+/// statistics, with the window's partial days and any days without daily statistics aggregated live.
+#[derive(Deserialize, Serialize, Copy, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum Normalization {
+    Window,
+    Day,
+    Rolling24h,
+}
+
+/// Stories by snooze: hide the ones still snoozed, include them, only those still snoozed, or only those whose
+/// snooze has passed, to resurface them
+#[derive(Deserialize, Serialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum SnoozedFilter {
+    Hide,
+    Include,
+    Active,
+    Due,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum ScoreScale {
+    Linear,
+    Log,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+enum CountMode {
+    Exact,
+    Estimate,
+}
+
+#[derive(Serialize)]
+struct StoryCount {
+    value: i64,
+    exact: bool,
+}
+
+/// Score statistics of the selected window, which z-scores are relative to; empty with other normalizations
+#[derive(Serialize)]
+struct WindowStats {
+    /// The scale the statistics are of, `log` being of ln(score + 1) like the z-scores
+    scale: ScoreScale,
+    #[serde(rename = "meanScore")]
+    mean_score: Option<f64>,
+    #[serde(rename = "stddevScore")]
+    stddev_score: Option<f64>,
+}
+
+/// Version of the /ranks/query response shape, bumped on incompatible changes
+const STORIES_RESPONSE_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct GetStoriesResponse {
+    version: u32,
+    stories: Vec<GetStory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<StoryCount>,
+    #[serde(rename = "nextCursor", skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    #[serde(rename = "prevCursor", skip_serializing_if = "Option::is_none")]
+    prev_cursor: Option<String>,
+    /// The filter after defaults and clamping were applied
+    filter: StoryRankingFilter,
+    stats: WindowStats,
+}
+
+#[derive(Serialize)]
+struct GetStory {
+    #[serde(rename = "storyId")]
+    story_id: i64,
+    score: i32,
+    timestamp: i64,
+    title: String,
+    url: String,
+    /// Username of the poster
+    by: Option<String>,
+    status: i32,
+    descendants: i32,
+    stars: Option<i32>,
+    flags: Option<i32>,
+    /// Names of the flags set, see `/ranks/flags`
+    #[serde(rename = "flagNames")]
+    flag_names: Vec<&'static str>,
+    /// When a snoozed story comes back
+    #[serde(rename = "snoozedUntil")]
+    snoozed_until: Option<i64>,
+    tags: Vec<String>,
+    /// Number of notes the user wrote on the story
+    #[serde(rename = "noteCount")]
+    note_count: i64,
+    /// Score relative to the stories it is normalized against, none when they all scored the same
+    #[serde(rename = "zScore")]
+    z_score: Option<f64>,
+    /// Percentage of the stories it is normalized against that scored lower
+    percentile: Option<f64>,
+    /// Normalized site, e.g. `bbc.co.uk` or `github.com/user`; none for stories without a URL
+    domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    highlights: Option<Highlights>,
+}
+
+/// Search matches as HTML, with the matching words in `<mark>` elements
+#[derive(Serialize)]
+struct Highlights {
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+/// Markers ts_headline puts around matching words, replaced once the text is HTML escaped
+const HIGHLIGHT_START: char = '\u{2}';
+const HIGHLIGHT_STOP: char = '\u{3}';
+
+fn highlight_html(headline: &str) -> String {
+    let text = htmlescape::decode_html(headline).unwrap_or_else(|_| String::from(headline));
+    htmlescape::encode_minimal(&text)
+        .replace(HIGHLIGHT_START, "<mark>")
+        .replace(HIGHLIGHT_STOP, "</mark>")
+}
+
+impl From<&tokio_postgres::row::Row> for GetStory {
+    fn from(row: &tokio_postgres::row::Row) -> Self {
+        let story_id = row.get(0);
+        let score = row.get(1);
+        let timestamp = row.get(2);
+        let title = row.get(3);
+        let url = row.get(4);
+        let status = row.get(5);
+        let descendants = row.get(6);
+        let stars = row.get(7);
+        let flags: Option<i32> = row.get(8);
+        let title_highlight: Option<&str> = row.get(11);
+        let note_highlight: Option<&str> = row.get(12);
+        let domain = row.get(13);
+        let by = row.get(14);
+        let z_score = row.get(15);
+        let percentile = row.get(16);
+        let snoozed_until = row.get(17);
+        let tags = row.get(18);
+        let note_count = row.get(19);
+        let highlights = title_highlight.map(|title| Highlights {
+            title: highlight_html(title),
+            note: note_highlight.filter(|n| n.contains(HIGHLIGHT_START)).map(highlight_html),
+        });
+        GetStory {
+            story_id,
+            score,
+            timestamp,
+            title: htmlescape::decode_html(title)
+                .map_or_else(|_| String::from(title), |v| v),
+            url,
+            by,
+            status,
+            descendants,
+            stars,
+            flags,
+            flag_names: flags::flag_names(flags.unwrap_or(0)),
+            snoozed_until,
+            tags,
+            note_count,
+            z_score,
+            percentile,
+            domain,
+            highlights,
+        }
+    }
+}
+
+struct QueryParameters {
+    query: String,
+    parameters: Vec<SqlParameter>,
+    sorts: Vec<(&'static SortKey, bool)>,
+    /// Whether rows are read backwards from a cursor, and must be reversed before display
+    reversed: bool,
+    /// The filter after defaults were applied
+    filter: StoryRankingFilter,
+    /// Counts every story matching the filter, using the first `count_parameters` parameters
+    count_query: String,
+    /// Explains the filtered rows as JSON, whose top plan node estimates the count, with the same parameters
+    estimate_query: String,
+    /// Selects the IDs of every story matching the filter, with the same parameters
+    ids_query: String,
+    count_parameters: usize,
+}
+
+impl QueryParameters {
+    fn cursor(&self, row: &tokio_postgres::row::Row, before: bool) -> String {
+        let keys = self.sorts.iter().enumerate()
+            .map(|(i, (key, _))| key.kind.read(row, SORT_KEY_COLUMN + i))
+            .collect();
+        let sorts_need_clock = self.sorts.iter().any(|(key, _)| key.needs_clock);
+        let has_ages = self.filter.terms.as_ref().is_some_and(FilterExpr::has_ages);
+        let as_of = if sorts_need_clock || has_ages { self.filter.as_of } else { None };
+        let gravity = if sorts_need_clock { self.filter.gravity } else { None };
+        StoryCursor { sorts: sort_signature(&self.sorts), keys, story_id: row.get(0), before, as_of, gravity }.encode()
+    }
+
+    /// Cursors before the first and after the last of the rows, in display order, when more stories may be there
+    fn page_cursors(&self, rows: &[tokio_postgres::row::Row]) -> (Option<String>, Option<String>) {
+        let full = rows.len() >= self.filter.page_size.unwrap_or(0) as usize;
+        let first_page = self.filter.cursor.is_none() && self.filter.page_number.unwrap_or(0) == 0;
+        let (has_prev, has_next) = if self.reversed {
+            (full, true)
+        } else {
+            (!first_page, full)
+        };
+
+        let prev = rows.first().filter(|_| has_prev).map(|row| self.cursor(row, true));
+        let next = rows.last().filter(|_| has_next).map(|row| self.cursor(row, false));
+        (prev, next)
+    }
+
+    /// Prepare and run the page query
+    async fn run(&self, conn: &mut PgConn, limits: QueryLimits) -> Result<Vec<tokio_postgres::row::Row>, WebError> {
+        run_query(conn, &self.query, &self.parameters, limits).await
+    }
+
+    /// Count the stories matching the filter, exactly or from the planner's row estimate
+    async fn count(&self, conn: &mut PgConn, mode: CountMode, limits: QueryLimits) -> Result<StoryCount, WebError> {
+        let parameters = &self.parameters[..self.count_parameters];
+        match mode {
+            CountMode::Exact => {
+                let rows = run_query(conn, &self.count_query, parameters, limits).await?;
+                let value = rows.first().map_or(0, |row| row.get::<_, i64>(0));
+                Ok(StoryCount { value, exact: true })
+            }
+            CountMode::Estimate => {
+                // a single row of a single plan, e.g. [{"Plan": {"Node Type": "Hash Join", "Plan Rows": 1234, ...}}]
+                let rows = run_query(conn, &self.estimate_query, parameters, limits).await?;
+                let value = rows.first()
+                    .and_then(|row| row.get::<_, serde_json::Value>(0)[0]["Plan"]["Plan Rows"].as_f64())
+                    .map_or(0, |rows| rows.round() as i64);
+                Ok(StoryCount { value, exact: false })
+            }
+        }
+    }
+}
+
+fn redacted_parameters(parameters: &[SqlParameter]) -> Vec<&'static str> {
+    parameters.iter().map(|p| p.type_name()).collect()
+}
+
+/// Prepare and run a query under the statement timeout, warning with redacted parameters when it is slow
+async fn run_query(conn: &mut PgConn, query: &str, parameters: &[SqlParameter],
+                   limits: QueryLimits) -> Result<Vec<tokio_postgres::row::Row>, WebError> {
+    let mut results = run_queries(conn, &[(query, parameters)], limits).await?;
+    Ok(results.pop().unwrap_or_default())
+}
+
+/// Runs the queries in order in one transaction, so later ones see the rows earlier ones locked or wrote, with the
+/// rows of each
+async fn run_queries(conn: &mut PgConn, queries: &[(&str, &[SqlParameter])],
+                     limits: QueryLimits) -> Result<Vec<Vec<tokio_postgres::row::Row>>, WebError> {
+    let started = Instant::now();
+    let cancel = CancelOnDrop::new(conn);
+    let result = queries_in_transaction(conn, queries, limits).await;
+    cancel.disarm();
+    let results = result?;
+
+    let elapsed = started.elapsed();
+    if elapsed >= limits.slow {
+        for (query, parameters) in queries {
+            tracing::warn!(
+                elapsed_ms = elapsed.as_millis() as u64,
+                query = %query,
+                parameters = ?redacted_parameters(parameters),
+                "slow query");
+        }
+    } else {
+        let rows: usize = results.iter().map(Vec::len).sum();
+        tracing::debug!(elapsed_ms = elapsed.as_millis() as u64, rows, "query complete");
+    }
+
+    Ok(results)
+}
+
+async fn queries_in_transaction(conn: &mut PgConn, queries: &[(&str, &[SqlParameter])],
+                                limits: QueryLimits) -> Result<Vec<Vec<tokio_postgres::row::Row>>, WebError> {
+    let txn = conn.transaction().await?;
+    txn.batch_execute(&format!("set local statement_timeout = {}", limits.timeout.as_millis())).await?;
+    let mut results = Vec::with_capacity(queries.len());
+    for (query, parameters) in queries {
+        let prep = txn.prepare(query)
+            .instrument(tracing::info_span!("prepare"))
+            .await?;
+
+        let rows = txn.query(
+            &prep,
+            &parameters.iter()
+                .map(|v| v.to_dynamic())
+                .collect::<Vec<_>>())
+            .instrument(tracing::info_span!("query"))
+            .await
+            .map_err(|e| query_guard::query_error(e, limits))?;
+        results.push(rows);
+    }
+    txn.commit().await?;
+    Ok(results)
+}
+
+/// Postgres type of a sort key's values, to read them from rows and bind them in cursor predicates
+#[derive(Copy, Clone)]
+enum SortKeyKind {
+    Int,
+    BigInt,
+    Float,
+    Text,
+}
+
+impl SortKeyKind {
+    fn read(&self, row: &tokio_postgres::row::Row, column: usize) -> CursorValue {
+        match self {
+            SortKeyKind::Int => CursorValue::Int(row.get::<_, i32>(column) as i64),
+            SortKeyKind::BigInt => CursorValue::Int(row.get::<_, i64>(column)),
+            SortKeyKind::Float => CursorValue::Float(row.get::<_, f64>(column)),
+            SortKeyKind::Text => CursorValue::Text(row.get::<_, String>(column)),
+        }
+    }
+
+    fn parameter(&self, value: &CursorValue) -> Option<SqlParameter> {
+        match self {
+            SortKeyKind::Int => value.as_i64()
+                .and_then(|v| std::convert::TryFrom::try_from(v).ok())
+                .map(SqlParameter::Int),
+            SortKeyKind::BigInt => value.as_i64().map(SqlParameter::BigInt),
+            SortKeyKind::Float => value.as_f64().map(SqlParameter::Float),
+            SortKeyKind::Text => value.as_str().map(|v| SqlParameter::from(String::from(v))),
+        }
+    }
+}
+
+struct SortKey {
+    name: &'static str,
+    expression: &'static str,
+    kind: SortKeyKind,
+    /// Only meaningful with a search, which binds the query `q` the expression uses
+    needs_search: bool,
+    /// Depends on the time of the query and the hot gravity, bound as `p.as_of` and `p.gravity`
+    needs_clock: bool,
+}
+
+/// Score relative to the mean and standard deviation of the stories it is compared with, null when they all score the
+/// same; computed with the percentile in `stats_with_clause`
+const Z_SCORE_EXPRESSION: &str = "s.z_score";
+
+/// Default gravity of the hot sort, the one Hacker News' front page has been described with
+const DEFAULT_GRAVITY: f64 = 1.8;
+
+/// Allowed sorts; every expression is non-null so keyset comparisons are well defined
+const SORT_KEYS: &[SortKey] = &[
+    SortKey { name: "timestamp", expression: "s.timestamp", kind: SortKeyKind::BigInt, needs_search: false, needs_clock: false },
+    SortKey { name: "score", expression: "s.score", kind: SortKeyKind::Int, needs_search: false, needs_clock: false },
+    SortKey { name: "stars", expression: "coalesce(r.stars, 0)", kind: SortKeyKind::Int, needs_search: false, needs_clock: false },
+    SortKey { name: "by", expression: "coalesce(s.by, '')", kind: SortKeyKind::Text, needs_search: false, needs_clock: false },
+    SortKey { name: "comments", expression: "s.descendants", kind: SortKeyKind::Int, needs_search: false, needs_clock: false },
+    SortKey {
+        name: "zScore",
+        expression: "coalesce(s.z_score, 0)",
+        kind: SortKeyKind::Float,
+        needs_search: false,
+        needs_clock: false,
+    },
+    SortKey {
+        name: "commentsPerPoint",
+        expression: "cast(s.descendants as float) / greatest(s.score, 1)",
+        kind: SortKeyKind::Float,
+        needs_search: false,
+        needs_clock: false,
+    },
+    // (points - 1) / (age in hours + 2) ^ gravity
+    SortKey {
+        name: "hot",
+        expression: "cast(greatest(s.score - 1, 0) as float)
+            / power(greatest(cast(p.as_of - s.timestamp as float) / 3600, 0) + 2, p.gravity)",
+        kind: SortKeyKind::Float,
+        needs_search: false,
+        needs_clock: true,
+    },
+    // points per hour, treating stories as at least six minutes old
+    SortKey {
+        name: "velocity",
+        expression: "cast(s.score as float) / greatest(cast(p.as_of - s.timestamp as float) / 3600, 0.1)",
+        kind: SortKeyKind::Float,
+        needs_search: false,
+        needs_clock: true,
+    },
+    SortKey {
+        name: "relevance",
+        expression: "cast(ts_rank(s.search || coalesce(r.search, ''::tsvector), q) as float)",
+        kind: SortKeyKind::Float,
+        needs_search: true,
+        needs_clock: false,
+    },
+];
+
+/// Index of the first selected sort key column, after the story columns, window statistics, highlights, domain,
+/// author, z-score, percentile, snooze time, tags and note count
+const SORT_KEY_COLUMN: usize = 20;
+
+fn sort_signature(sorts: &[(&'static SortKey, bool)]) -> Vec<String> {
+    sorts.iter()
+        .map(|(key, asc)| format!("{} {}", key.name, if *asc { "asc" } else { "desc" }))
+        .collect()
+}
+
+/// Rows after the cursor in reading order: (k1 > v1) or (k1 = v1 and k2 > v2) or ... and finally the story ID
+fn keyset_predicate(sorts: &[(&'static SortKey, bool)], reversed: bool, cursor: &StoryCursor,
+                    parameters: &mut Parameters) -> Result<Predicate, WebError> {
+    let mut placeholders = Vec::with_capacity(sorts.len());
+    for ((key, _), value) in sorts.iter().zip(cursor.keys.iter()) {
+        let parameter = key.kind.parameter(value).ok_or_else(|| WebError::Validation(
+            String::from("cursor"), String::from("Invalid cursor")))?;
+        placeholders.push(parameters.bind(parameter));
+    }
+
+    let story_id = parameters.bind(cursor.story_id);
+    let after = |asc: bool| if asc { Comparison::Gt } else { Comparison::Lt };
+
+    let mut alternatives = vec![];
+    for i in 0..=sorts.len() {
+        let mut terms: Vec<Predicate> = (0..i)
+            .map(|j| Predicate::compare(sorts[j].0.expression, Comparison::Eq, placeholders[j]))
+            .collect();
+        if i < sorts.len() {
+            let (key, asc) = sorts[i];
+            terms.push(Predicate::compare(key.expression, after(asc), placeholders[i]));
+        } else {
+            terms.push(Predicate::compare("s.story_id", after(reversed), story_id));
+        }
+
+        alternatives.push(Predicate::All(terms));
+    }
+
+    Ok(Predicate::Any(alternatives))
+}
+
+/// Mean and sample standard deviation combined from the daily sums of `story_daily_stats`
+fn combined_stats(sum: &str, squares: &str) -> (String, String) {
+    (format!("sum({}) / nullif(sum(story_count), 0)", sum),
+     format!("sqrt(greatest(sum({q}) - sum({s}) ^ 2 / nullif(sum(story_count), 0), 0) / nullif(sum(story_count) - 1, 0))",
+             q = squares, s = sum))
+}
+
+/// Mean and sample standard deviation of a single day of `story_daily_stats`
+fn day_stats(sum: &str, squares: &str) -> (String, String) {
+    (format!("{} / nullif(story_count, 0)", sum),
+     format!("sqrt(greatest({q} - {s} ^ 2 / nullif(story_count, 0), 0) / nullif(story_count - 1, 0))",
+             q = squares, s = sum))
+}
+
+/// The predicates of the bounds given, and'ed
+fn bounds_sql(gt: Option<Placeholder>, lt: Option<Placeholder>, lower: impl Fn(Placeholder) -> String,
+              upper: impl Fn(Placeholder) -> String) -> String {
+    Predicate::All(gt.map(lower).into_iter().chain(lt.map(upper)).map(Predicate::sql).collect()).to_sql()
+}
+
+/// Columns of `story_daily_stats` that statistics are combined from, aggregated from the stories themselves
+const LIVE_STATS_COLUMNS: &str = "count(*) story_count
+    , sum(cast(score as float)) score_sum, sum(cast(score as float) ^ 2) score_sum_squares
+    , sum(ln(greatest(score, 0) + 1)) log_score_sum, sum(ln(greatest(score, 0) + 1) ^ 2) log_score_sum_squares";
+
+/// Defines `scored_stories`, the stories with the mean and standard deviation of the scaled scores they are compared
+/// with as `mean_x` and `stddev_x`, their z-score and their percentile among them, given the placeholders of the
+/// timestamp filter's bounds.
+///
+/// Window and day statistics combine the precomputed daily statistics with the stories of the days those do not
+/// cover, which for the window are the days it only partly covers and for both are the days hnstar-sync has not
+/// summarized yet. Rolling statistics are computed from the stories.
+fn with_scored_stories(query: &mut Select, normalization: Normalization, scale: ScoreScale,
+                       gt: Option<Placeholder>, lt: Option<Placeholder>) {
+    let window = Predicate::All(gt.map(|p| Predicate::compare("timestamp", Comparison::Gt, p)).into_iter()
+        .chain(lt.map(|p| Predicate::compare("timestamp", Comparison::Lt, p)))
+        .collect()).to_sql();
+    // the days entirely in the window, the days it touches and their stories, and for rolling statistics the
+    // stories up to half a day beyond it
+    let full_days = bounds_sql(gt, lt, |p| format!("day * 86400 > cast({} as bigint)", p),
+                               |p| format!("(day + 1) * 86400 <= cast({} as bigint)", p));
+    let days = bounds_sql(gt, lt, |p| format!("day >= cast({} as bigint) / 86400", p),
+                          |p| format!("day <= cast({} as bigint) / 86400", p));
+    let day_stories = bounds_sql(gt, lt, |p| format!("timestamp >= cast({} as bigint) / 86400 * 86400", p),
+                                 |p| format!("timestamp < (cast({} as bigint) / 86400 + 1) * 86400", p));
+    let rolled_stories = bounds_sql(gt, lt, |p| format!("timestamp > cast({} as bigint) - 43200", p),
+                                    |p| format!("timestamp < cast({} as bigint) + 43200", p));
+
+    // z-scores are taken of the scaled score, so one huge story does not dwarf the rest on the log scale
+    let scaled = |score: &str| match scale {
+        ScoreScale::Linear => format!("cast({} as float)", score),
+        ScoreScale::Log => format!("ln(greatest({}, 0) + 1)", score),
+    };
+    let (mean_x, stddev_x) = match scale {
+        ScoreScale::Linear => combined_stats("score_sum", "score_sum_squares"),
+        ScoreScale::Log => combined_stats("log_score_sum", "log_score_sum_squares"),
+    };
+
+    match normalization {
+        Normalization::Window => query
+            .with("full_days", format!("
+            select day, story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares
+            from hnstar.story_daily_stats
+            where {}", full_days))
+            .with("stats", format!("
+            select {mean_x} mean_x, {stddev_x} stddev_x
+            from (
+                select story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares from full_days
+                union all
+                select {live}
+                from hnstar.story
+                where {window} and not exists (select 1 from full_days d where d.day = story.timestamp / 86400)
+            ) parts", mean_x = mean_x, stddev_x = stddev_x, live = LIVE_STATS_COLUMNS, window = window))
+            .with("scored_stories", format!("
+            select story.*, stats.mean_x, stats.stddev_x
+                , ({story_x} - stats.mean_x) / nullif(stats.stddev_x, 0) z_score
+                , 100 * percent_rank() over (order by story.score) percentile
+            from hnstar.story story, stats
+            where {window}", story_x = scaled("story.score"), window = window)),
+        Normalization::Day => query
+            .with("stats", format!("
+            select day, {mean_x} mean_x, {stddev_x} stddev_x
+            from (
+                select day, story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares
+                from hnstar.story_daily_stats
+                where {days}
+                union all
+                select timestamp / 86400, {live}
+                from hnstar.story
+                where {day_stories}
+                    and not exists (select 1 from hnstar.story_daily_stats d where d.day = story.timestamp / 86400)
+                group by timestamp / 86400
+            ) parts
+            group by day", mean_x = mean_x, stddev_x = stddev_x, live = LIVE_STATS_COLUMNS, days = days,
+                day_stories = day_stories))
+            .with("scored_stories", format!("
+            select story.*, stats.mean_x, stats.stddev_x
+                , ({story_x} - stats.mean_x) / nullif(stats.stddev_x, 0) z_score
+                , 100 * percent_rank() over (partition by stats.day order by story.score) percentile
+            from hnstar.story story join stats on stats.day = story.timestamp / 86400",
+                story_x = scaled("story.score"))),
+        // percent_rank ignores window frames, so the rolling percentile counts the lower scores itself
+        Normalization::Rolling24h => query
+            .with("rolled", format!("
+            select *, avg({x}) over day mean_x, stddev({x}) over day stddev_x
+            from hnstar.story
+            where {bounds}
+            window day as (order by timestamp
+                range between cast(43200 as bigint) preceding and cast(43200 as bigint) following)",
+                x = scaled("score"), bounds = rolled_stories))
+            .with("scored_stories", format!("
+            select rolled.*
+                , ({x} - mean_x) / nullif(stddev_x, 0) z_score
+                , (select 100 * cast(count(*) filter (where o.score < rolled.score) as float) / nullif(count(*) - 1, 0)
+                   from hnstar.story o
+                   where o.timestamp between rolled.timestamp - 43200 and rolled.timestamp + 43200) percentile
+            from rolled", x = scaled("score"))),
+    };
+}
+
+fn get_query(model: &StoryRankingFilter, user_id: i32, domains: &DomainNormalizer) -> Result<QueryParameters, WebError> {
+    let span = tracing::info_span!("build_query");
+    let _enter = span.enter();
+    if model.cursor.is_some() && model.page_number.is_some_and(|n| n > 0) {
+        return Err(WebError::Validation(
+            String::from("cursor"), String::from("Specify either cursor or pageNumber, not both")));
+    }
+
+    let mut model = model.with_defaults();
+    if let Some(q) = model.q.take() {
+        let parsed = query_language::parse(&q)?;
+        model.terms = Some(match model.terms.take() {
+            Some(terms) => FilterExpr::And { terms: vec![terms, parsed] },
+            None => parsed,
+        });
+    }
+
+    for (field, regex) in [("title", &model.title), ("url", &model.url), ("comment", &model.comment)].iter() {
+        if let Some(regex) = regex {
+            query_guard::validate_regex(&format!("{}.regex", field), &regex.regex)?;
+        }
+    }
+
+    let ts = model.timestamp.unwrap();
+    if ts.gt.is_none() && ts.lt.is_none() {
+        return Err(WebError::Validation(String::from("timestamp"), String::from("Must specify timestamp filter")));
+    }
+
+    // a cursor pointing before a page is followed by reading backwards from it
+    let cursor = match &model.cursor {
+        Some(cursor) => Some(StoryCursor::decode(cursor)?),
+        None => None,
+    };
+    let reversed = cursor.as_ref().is_some_and(|c| c.before);
+    if let Some(cursor) = &cursor {
+        model.as_of = cursor.as_of.or(model.as_of);
+        model.gravity = cursor.gravity.or(model.gravity);
+    }
+
+    // sorting keys, also selected so the cursors can be made from the first and last rows
+    let sorts: Vec<(&'static SortKey, bool)> = model.sort.iter().flatten()
+        .filter_map(|sort| SORT_KEYS.iter().find(|key| key.name == sort.sort).map(|key| (key, sort.asc)))
+        .collect();
+    if model.search.is_none() {
+        if let Some((key, _)) = sorts.iter().find(|(key, _)| key.needs_search) {
+            return Err(WebError::Validation(
+                String::from("sort"), format!("Sorting by {} requires a search", key.name)));
+        }
+    }
+
+    let sort_signature = sort_signature(&sorts);
+    let read_sorts: Vec<(&'static SortKey, bool)> = sorts.iter()
+        .map(|(key, asc)| (*key, *asc != reversed))
+        .collect();
+
+    let mut parameters = Parameters::new();
+    let mut query = Select::from("scored_stories s");
+    query.column("s.story_id, s.score, s.timestamp, s.title, s.url")
+        .column("s.status, s.descendants, r.stars, r.flags")
+        .column("cast(s.mean_x as float), cast(s.stddev_x as float)");
+    if model.search.is_some() {
+        query.column("ts_headline('english', s.title, q, 'HighlightAll=true, StartSel=' || chr(2) || ', StopSel=' || chr(3))")
+            .column("ts_headline('english', r.comment, q, 'MaxFragments=2, StartSel=' || chr(2) || ', StopSel=' || chr(3))");
+    } else {
+        query.column("cast(null as text), cast(null as text)");
+    }
+    query.column("nullif(s.domain, ''), s.by")
+        .column(format!("{}, s.percentile", Z_SCORE_EXPRESSION))
+        .column("cast(extract(epoch from r.snoozed_until) as bigint)");
+
+    let user = parameters.bind(user_id);
+    query.column(format!("array(
+            select t.name from hnstar.story_tag st join hnstar.tag t on t.tag_id = st.tag_id
+            where st.story_id = s.story_id and t.user_main_id = {}
+            order by t.name)", user))
+        .column(format!(
+            "(select count(*) from hnstar.story_note n where n.story_id = s.story_id and n.user_main_id = {})", user));
+    for (i, (key, _)) in sorts.iter().enumerate() {
+        query.column(format!("{} as sort_key_{}", key.expression, i));
+    }
+
+    query.join(Join::Left(String::from("hnstar.story_user_rank r"), vec![
+        Predicate::sql("r.story_id = s.story_id"),
+        Predicate::compare("r.user_main_id", Comparison::Eq, user),
+    ]));
+
+    let gt_parameter = ts.gt.map(|gt_ts| parameters.bind(gt_ts));
+    let lt_parameter = ts.lt.map(|lt_ts| parameters.bind(lt_ts));
+    for (bound, comparison) in [(gt_parameter, Comparison::Gt), (lt_parameter, Comparison::Lt)].iter() {
+        if let Some(bound) = bound {
+            query.filter(Predicate::compare("timestamp", *comparison, *bound));
+        }
+    }
+
+    with_scored_stories(&mut query, model.normalization.unwrap(), model.z_score_scale.unwrap(), gt_parameter, lt_parameter);
+
+    // time-dependent sorts age stories to the same moment on every page
+    if sorts.iter().any(|(key, _)| key.needs_clock) {
+        let as_of = parameters.bind(model.as_of.unwrap());
+        let gravity = parameters.bind(model.gravity.unwrap());
+        query.join(Join::Cross(format!("(select cast({} as bigint) as_of, cast({} as float) gravity) p", as_of, gravity)));
+    }
+
+    // the search query is bound once as `q` for filtering, ranking and highlighting
+    if let Some(search) = &model.search {
+        let search = parameters.bind(search.clone());
+        query.join(Join::Cross(format!("websearch_to_tsquery('english', {}) q", search)))
+            .filter(Predicate::sql("(s.search @@ q or r.search @@ q)"));
+    }
+
+    // Combine title, comment, and url filters with OR instead of AND
+    let regexes = [("title", &model.title), ("comment", &model.comment), ("url", &model.url)];
+    if regexes.iter().any(|(_, regex)| regex.is_some()) {
+        let mut matches = vec![];
+        for (column, regex) in regexes.iter() {
+            if let Some(regex) = regex {
+                let pattern = parameters.bind(regex.regex.clone());
+                matches.push(Predicate::sql(format!("{} {} {}", column, if regex.not { "!~*" } else { "~*" }, pattern)));
+            }
+        }
+
+        query.filter(Predicate::Any(matches));
+    }
+
+    if let Some(domain) = &model.domain {
+        let normalized = |given: &Vec<String>| -> Vec<String> { given.iter().map(|d| normalize_domain(domains, d)).collect() };
+        if let Some(include) = domain.include.as_ref().filter(|d| !d.is_empty()) {
+            query.filter(domain_predicate(parameters.bind(normalized(include))));
+        }
+
+        if let Some(exclude) = domain.exclude.as_ref().filter(|d| !d.is_empty()) {
+            query.filter(domain_predicate(parameters.bind(normalized(exclude))).negate());
+        }
+    }
+
+    if let Some(by) = &model.by {
+        if let Some(include) = by.include.as_ref().filter(|b| !b.is_empty()) {
+            query.filter(Predicate::sql(format!("s.by = any({})", parameters.bind(include.clone()))));
+        }
+
+        if let Some(exclude) = by.exclude.as_ref().filter(|b| !b.is_empty()) {
+            query.filter(Predicate::sql(format!("s.by = any({})", parameters.bind(exclude.clone()))).negate());
+        }
+    }
+
+    if let Some(notes) = &model.notes {
+        query.filter(notes_predicate(user, parameters.bind(notes.clone())));
+    }
+
+    if let Some(tags) = &model.tags {
+        if let Some(include) = tags.include.as_ref().filter(|t| !t.is_empty()) {
+            query.filter(tag_predicate(user, parameters.bind(normalize_tags("tags.include", include)?)));
+        }
+
+        if let Some(exclude) = tags.exclude.as_ref().filter(|t| !t.is_empty()) {
+            query.filter(tag_predicate(user, parameters.bind(normalize_tags("tags.exclude", exclude)?)).negate());
+        }
+    }
+
+    if let Some(score) = &model.score {
+        if let Some(gt_score) = score.gt {
+            query.filter(Predicate::compare("score", Comparison::Gt, parameters.bind(gt_score)));
+        }
+
+        if let Some(lt_score) = score.lt {
+            query.filter(Predicate::compare("score", Comparison::Lt, parameters.bind(lt_score)));
+        }
+    }
+
+    // z_score with mean and stddev over timestamp range
+    if let Some(z_score) = &model.z_score {
+        if let Some(gt_z_score) = z_score.gt {
+            query.filter(Predicate::compare(Z_SCORE_EXPRESSION, Comparison::Ge, parameters.bind(gt_z_score)));
+        }
+
+        if let Some(lt_z_score) = z_score.lt {
+            query.filter(Predicate::compare(Z_SCORE_EXPRESSION, Comparison::Le, parameters.bind(lt_z_score)));
+        }
+    }
+
+    if let Some(percentile) = &model.percentile {
+        if let Some(gt_percentile) = percentile.gt {
+            query.filter(Predicate::compare("s.percentile", Comparison::Ge, parameters.bind(gt_percentile)));
+        }
+
+        if let Some(lt_percentile) = percentile.lt {
+            query.filter(Predicate::compare("s.percentile", Comparison::Le, parameters.bind(lt_percentile)));
+        }
+    }
+
+    if let Some(stars) = model.stars {
+        if let Some(gt_stars) = stars.gt {
+            query.filter(Predicate::compare("stars", Comparison::Gt, parameters.bind(gt_stars)));
+        }
+
+        if let Some(lt_stars) = stars.lt {
+            query.filter(Predicate::compare("stars", Comparison::Lt, parameters.bind(lt_stars)));
+        }
+    }
+
+    if let Some(status) = &model.status {
+        query.filter(status.to_sql("s.status", &mut parameters));
+    }
+
+    if let Some(flags) = &model.flags {
+        query.filter(flags.to_sql("r.flags", &mut parameters)?);
+    }
+
+    if let Some(terms) = &model.terms {
+        query.filter(terms.to_sql(&mut parameters, user, model.as_of.unwrap(), domains)?);
+    }
+
+    let read = Predicate::sql(format!("coalesce(r.flags, 0) & {} <> 0", flags::READ_FLAG));
+    if let Some(is_read) = model.read {
+        query.filter(if is_read { read.clone() } else { read.clone().negate() });
+    }
+
+    let snoozed = Predicate::sql("r.snoozed_until is not null");
+    let snoozing = Predicate::sql("r.snoozed_until > now()");
+    match model.snoozed.unwrap() {
+        SnoozedFilter::Hide => { query.filter(snoozing.negate()); }
+        SnoozedFilter::Include => {}
+        SnoozedFilter::Active => { query.filter(snoozing); }
+        SnoozedFilter::Due => { query.filter(snoozed).filter(snoozing.negate()); }
+    }
+
+    // the last visit is when the user last marked stories read, so anonymous users have never visited
+    if model.new_since_last_visit.unwrap_or(false) {
+        query.filter(read.negate())
+            .filter(Predicate::sql(format!("s.timestamp > coalesce((
+                select cast(extract(epoch from read) as bigint)
+                from hnstar.user_last_read
+                where user_main_id = {}), 0)", user)));
+    }
+
+    // counting and marking stories read ignore paging, so they only use the parameters bound so far
+    let count_query = query.count_sql();
+    let estimate_query = format!("explain (format json) {}", query.rows_sql());
+    let ids_query = query.filtered_sql("s.story_id");
+    let count_parameters = parameters.len();
+
+    for (key, asc) in read_sorts.iter() {
+        query.order_by(key.expression, *asc);
+    }
+    query.order_by("s.story_id", reversed);
+
+    // paging, from the cursor's position when given, otherwise by page number
+    let page_size = model.page_size.unwrap();
+    query.limit(page_size as i64);
+    if let Some(cursor) = &cursor {
+        if cursor.sorts != sort_signature {
+            return Err(WebError::Validation(
+                String::from("cursor"), String::from("Cursor was created for a different sort")));
+        }
+
+        query.filter(keyset_predicate(&read_sorts, reversed, cursor, &mut parameters)?);
+    } else {
+        query.offset((model.page_number.unwrap_or(0) * page_size) as i64);
+    }
+
+    let query = query.to_sql();
+    let parameters = parameters.into_vec();
+    tracing::debug!(query = %query, "built query");
+    tracing::trace!(parameters = ?parameters, "query parameters");
+    Ok(QueryParameters { query, parameters, sorts, reversed, filter: model, count_query, estimate_query, ids_query,
+                         count_parameters })
+}
+
+fn clamp_min(i: i32, min: i32) -> i32 {
+    if i < min { min } else { i }
+}
+
+fn clamp(i: i32, min: i32, max: i32) -> i32 {
+    if i > max {
+        max
+    } else if i < min {
+        min
+    } else { i }
+}
+
+async fn do_get_story_ranking(auth: &mut AuthenticatedConnection, user_id: i32, model: &StoryRankingFilter,
+                              data: &AppState) -> Result<String, WebError> {
+    let query = get_query(model, user_id, &data.domains)?;
+    let started = Instant::now();
+    let mut rows = query.run(&mut auth.conn, data.limits).await?;
+    data.metrics.record_query(started.elapsed(), model.active_filters(), rows.len());
+    if query.reversed {
+        rows.reverse();
+    }
+
+    let count = match model.count {
+        Some(mode) => Some(query.count(&mut auth.conn, mode, data.limits).await?),
+        None => None,
+    };
+
+    let (prev_cursor, next_cursor) = query.page_cursors(&rows);
+    // per day and rolling statistics differ between stories, so only the window's are summarized
+    let window_row = rows.first().filter(|_| query.filter.normalization == Some(Normalization::Window));
+    let stats = WindowStats {
+        scale: query.filter.z_score_scale.unwrap_or(ScoreScale::Linear),
+        mean_score: window_row.and_then(|row| row.get(9)),
+        stddev_score: window_row.and_then(|row| row.get(10)),
+    };
+    let response = GetStoriesResponse {
+        version: STORIES_RESPONSE_VERSION,
+        stories: rows.iter().map(GetStory::from).collect(),
+        count,
+        next_cursor,
+        prev_cursor,
+        filter: query.filter,
+        stats,
+    };
+    Ok(serde_json::to_string(&response)?)
+}
+
+async fn do_get_story_count(auth: &mut AuthenticatedConnection, user_id: i32, model: &StoryRankingFilter,
+                            data: &AppState) -> Result<String, WebError> {
+    let query = get_query(model, user_id, &data.domains)?;
+    let count = query.count(&mut auth.conn, model.count.unwrap_or(CountMode::Exact), data.limits).await?;
+    Ok(serde_json::to_string(&count)?)
+}
+
+/// Which posters to summarize: everyone who posted in the window, or only the given usernames
+#[derive(Deserialize)]
+struct AuthorStatsFilter {
+    timestamp: Option<BigIntFilter>,
+    by: Option<Vec<String>>,
+    /// Leave out posters with fewer stories in the window, default 1
+    #[serde(rename = "minStories")]
+    min_stories: Option<i64>,
+    /// Number of posters, default 50 and at most 500
+    limit: Option<i32>,
+    /// Highest scoring stories listed per poster, default 3 and at most 10
+    #[serde(rename = "topStories")]
+    top_stories: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AuthorStory {
+    #[serde(rename = "storyId")]
+    story_id: i64,
+    title: String,
+    score: i32,
+    timestamp: i64,
+}
+
+#[derive(Serialize)]
+struct AuthorStats {
+    by: String,
+    stories: i64,
+    #[serde(rename = "medianScore")]
+    median_score: f64,
+    #[serde(rename = "topStories")]
+    top_stories: Vec<AuthorStory>,
+}
+
+impl From<&tokio_postgres::row::Row> for AuthorStats {
+    fn from(row: &tokio_postgres::row::Row) -> Self {
+        let story_ids: Vec<i64> = row.get(3);
+        let titles: Vec<String> = row.get(4);
+        let scores: Vec<i32> = row.get(5);
+        let timestamps: Vec<i64> = row.get(6);
+        let top_stories = story_ids.into_iter().zip(titles).zip(scores).zip(timestamps)
+            .map(|(((story_id, title), score), timestamp)| AuthorStory {
+                story_id,
+                title: htmlescape::decode_html(&title).unwrap_or(title),
+                score,
+                timestamp,
+            })
+            .collect();
+        AuthorStats { by: row.get(0), stories: row.get(1), median_score: row.get(2), top_stories }
+    }
+}
+
+/// Posters ranked by how many stories they posted in the window, then by their median score
+async fn do_get_author_stats(conn: &mut PgConn, model: &AuthorStatsFilter, data: &AppState) -> Result<String, WebError> {
+    let ts = model.timestamp.unwrap_or(BigIntFilter {
+        gt: Some((chrono::Utc::now() + Duration::days(-30)).timestamp()),
+        lt: None,
+    });
+    if ts.gt.is_none() && ts.lt.is_none() {
+        return Err(WebError::Validation(String::from("timestamp"), String::from("Must specify timestamp filter")));
+    }
+
+    let mut parameters = Parameters::new();
+    let mut window_stories = Select::from("hnstar.story");
+    window_stories.column("story_id, by, title, score, timestamp")
+        .column("row_number() over (partition by by order by score desc, story_id desc) score_rank")
+        .filter(Predicate::sql("by is not null"))
+        .filter(Predicate::compare("timestamp", Comparison::Gt, parameters.bind(ts.gt.unwrap_or(i64::MIN))))
+        .filter(Predicate::compare("timestamp", Comparison::Lt, parameters.bind(ts.lt.unwrap_or(i64::MAX))));
+    if let Some(by) = model.by.as_ref().filter(|b| !b.is_empty()) {
+        window_stories.filter(Predicate::sql(format!("by = any({})", parameters.bind(by.clone()))));
+    }
+
+    let top_stories = parameters.bind(model.top_stories.unwrap_or(3).clamp(1, 10));
+    let min_stories = parameters.bind(model.min_stories.unwrap_or(1));
+    let mut query = Select::from("window_stories");
+    query.with("window_stories", window_stories.to_sql())
+        .column("by, count(*), cast(percentile_cont(0.5) within group (order by score) as float)");
+    for column in ["story_id", "title", "score", "timestamp"].iter() {
+        query.column(format!("array_agg({} order by score_rank) filter (where score_rank <= {})", column, top_stories));
+    }
+    query.group_by("by")
+        .having(Predicate::compare("count(*)", Comparison::Ge, min_stories))
+        .order_by("2", false)
+        .order_by("3", false)
+        .order_by("by", true)
+        .limit(clamp(model.limit.unwrap_or(50), 1, 500) as i64);
+
+    let parameters = parameters.into_vec();
+    let query = query.to_sql();
+    let rows = run_query(conn, &query, &parameters, data.limits).await?;
+    let authors: Vec<AuthorStats> = rows.iter().map(AuthorStats::from).collect();
+    Ok(serde_json::to_string(&authors)?)
+}
+
+#[post("/query")]
+async fn get_author_stats(req: HttpRequest, data: web::Data<AppState>, model: web::Json<AuthorStatsFilter>) -> impl Responder {
+    let mut conn = match data.conn().await {
+        Ok(conn) => conn,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_get_author_stats(&mut conn, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+/// Quantile levels of the score and comment quantiles in `story_daily_stats`
+const DAILY_QUANTILES: &[f64] = &[0.1, 0.25, 0.5, 0.75, 0.9, 0.99];
+
+#[derive(Deserialize)]
+struct DailyStatsFilter {
+    timestamp: Option<BigIntFilter>,
+}
+
+#[derive(Serialize)]
+struct DailyStats {
+    /// Start of the UTC day
+    timestamp: i64,
+    count: i64,
+    #[serde(rename = "meanScore")]
+    mean_score: Option<f64>,
+    #[serde(rename = "stddevScore")]
+    stddev_score: Option<f64>,
+    #[serde(rename = "scoreQuantiles")]
+    score_quantiles: Vec<f64>,
+    #[serde(rename = "meanDescendants")]
+    mean_descendants: Option<f64>,
+    #[serde(rename = "stddevDescendants")]
+    stddev_descendants: Option<f64>,
+    #[serde(rename = "descendantsQuantiles")]
+    descendants_quantiles: Vec<f64>,
+}
+
+#[derive(Serialize)]
+struct DailyStatsResponse {
+    /// Levels of the quantiles of every day
+    quantiles: &'static [f64],
+    days: Vec<DailyStats>,
+}
+
+async fn do_get_daily_stats(conn: &mut PgConn, model: &DailyStatsFilter, data: &AppState) -> Result<String, WebError> {
+    let ts = model.timestamp.unwrap_or(BigIntFilter {
+        gt: Some((chrono::Utc::now() + Duration::days(-30)).timestamp()),
+        lt: None,
+    });
+    let mut parameters = Parameters::new();
+    let (mean_score, stddev_score) = day_stats("score_sum", "score_sum_squares");
+    let (mean_descendants, stddev_descendants) = day_stats("descendants_sum", "descendants_sum_squares");
+    let mut query = Select::from("hnstar.story_daily_stats");
+    query.column("day * 86400, story_count")
+        .column(format!("{}, {}, score_quantiles", mean_score, stddev_score))
+        .column(format!("{}, {}, descendants_quantiles", mean_descendants, stddev_descendants))
+        .filter(Predicate::sql(format!("day >= cast({} as bigint) / 86400", parameters.bind(ts.gt.unwrap_or(i64::MIN)))))
+        .filter(Predicate::sql(format!("day <= cast({} as bigint) / 86400", parameters.bind(ts.lt.unwrap_or(i64::MAX)))))
+        .order_by("day", true);
+
+    let parameters = parameters.into_vec();
+    let query = query.to_sql();
+    let rows = run_query(conn, &query, &parameters, data.limits).await?;
+    let days = rows.iter()
+        .map(|row| DailyStats {
+            timestamp: row.get(0),
+            count: row.get(1),
+            mean_score: row.get(2),
+            stddev_score: row.get(3),
+            score_quantiles: row.get(4),
+            mean_descendants: row.get(5),
+            stddev_descendants: row.get(6),
+            descendants_quantiles: row.get(7),
+        })
+        .collect();
+    Ok(serde_json::to_string(&DailyStatsResponse { quantiles: DAILY_QUANTILES, days })?)
+}
+
+#[post("/daily")]
+async fn get_daily_stats(req: HttpRequest, data: web::Data<AppState>, model: web::Json<DailyStatsFilter>) -> impl Responder {
+    let mut conn = match data.conn().await {
+        Ok(conn) => conn,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    match do_get_daily_stats(&mut conn, &model, &data).await {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[post("/query")]
+async fn get_story_ranking(req: HttpRequest, data: web::Data<AppState>, model: web::Json<StoryRankingFilter>) -> impl Responder {
+    let mut auth = match data.authenticate_or_anonymous(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    let user_id = auth.user.user_id;
+    let result = do_get_story_ranking(&mut auth, user_id, &model, &data).await;
+    match result {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+#[post("/count")]
+async fn get_story_count(req: HttpRequest, data: web::Data<AppState>, model: web::Json<StoryRankingFilter>) -> impl Responder {
+    let mut auth = match data.authenticate_or_anonymous(&req, &data).await {
+        Ok(auth) => auth,
+        Err(err) => { return err.to_response(&req); }
+    };
+
+    let user_id = auth.user.user_id;
+    let result = do_get_story_count(&mut auth, user_id, &model, &data).await;
+    match result {
+        Ok(json) => HttpResponse::Ok().content_type("application/json").body(json),
+        Err(err) => err.to_response(&req)
+    }
+}
+
+/// Keep the newest story timestamp fresh for the story age gauge
+async fn refresh_newest_story(data: AppState) {
+    let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let newest = match data.conn().await {
+            Ok(conn) => conn.query_one("select max(timestamp) from hnstar.story", &[]).await
+                .map(|row| row.get::<_, Option<i64>>(0)),
+            Err(err) => {
+                tracing::warn!(error = ?err, "Could not get a connection to refresh newest story");
+                continue;
+            }
+        };
+
+        match newest {
+            Ok(Some(newest)) => data.metrics.set_newest_story_timestamp(newest),
+            Ok(None) => {}
+            Err(err) => tracing::warn!(error = ?err, "Could not refresh newest story"),
+        }
+    }
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    // Log level and per-module directives come from HNSTAR_LOG, e.g. "info,hnstar=debug"
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_env("HNSTAR_LOG").unwrap_or_else(|_| EnvFilter::new("info")))
+        .init();
+
+    let addr_port = match std::env::var("BIND_ADDR_PORT") {
+        Ok(bind) => bind.to_socket_addrs().unwrap().next().unwrap(),
+        Err(_) => {
+            tracing::info!("No BIND_ADDR_PORT environment variable, defaulting to localhost:8000");
+            String::from("127.0.0.1:8000").to_socket_addrs().unwrap().next().unwrap()
+        }
+    };
+
+    let secure = if std::env::var("PRIVATE_KEY_FILE").is_ok() ||
+        std::env::var("CERTIFICATE_FILE").is_ok() {
+        use std::path::{Path};
+        let private_key_file = std::env::var("PRIVATE_KEY_FILE")
             .map(|v| Path::new(&v).to_owned())
             .map_or(None, |p| if p.is_file() { Some(p) } else { None });
         if private_key_file.is_none() {
-            println!("No PRIVATE_KEY_FILE found");
+            tracing::error!("No PRIVATE_KEY_FILE found");
             return Ok(());
         }
 
@@ -599,7 +2658,7 @@ async fn main() -> std::io::Result<()> {
             .map(|v| Path::new(&v).to_owned())
             .map_or(None, |p| if p.is_file() { Some(p) } else { None });
         if certificate_file.is_none() {
-            println!("No CERTIFICATE_FILE found");
+            tracing::error!("No CERTIFICATE_FILE found");
             return Ok(());
         }
 
@@ -614,82 +2673,153 @@ async fn main() -> std::io::Result<()> {
         None
     };
 
-    let server = HttpServer::new(|| {
-        let pg_url = match std::env::var("POSTGRESQL_URL") {
-            Ok(pg) => pg,
-            Err(e) => {
-                println!("Could not find POSTGRESQL_URL as an environment variable");
-                panic!(e)
+    let pg_url = match std::env::var("POSTGRESQL_URL") {
+        Ok(pg) => pg,
+        Err(e) => {
+            tracing::error!("Could not find POSTGRESQL_URL as an environment variable");
+            panic!(e)
+        }
+    };
+
+    let config = match pg_url.parse() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Invalid POSTGRESQL_URL found");
+            panic!(e)
+        }
+    };
+
+    // Shared by all workers, so pool and business metrics describe the whole process
+    let manager_config = ManagerConfig { recycling_method: RecyclingMethod::Fast };
+    let manager = Manager::from_config(config, NoTls, manager_config);
+    let pool = Pool::new(manager, 30);
+
+    let auth_url = std::env::var("MINIAUTHURE_URL").ok();
+    if auth_url.is_none() {
+        panic!("No MINIAUTHURE_URL found")
+    }
+
+    let auth_url = String::from(auth_url.unwrap());
+    let auth_client = reqwest::ClientBuilder::new()
+        .danger_accept_invalid_certs(true)
+        .build().unwrap();
+    let limits = QueryLimits::from_env();
+
+    let exporter = opentelemetry_prometheus::exporter().init();
+    let meter = global::meter("hnstar");
+    let metrics = Arc::new(Metrics::new(&meter, &pool));
+    let domains = Arc::new(DomainNormalizer::from_env().await);
+    let my_app_state = AppState { pool, auth_url, auth_client, limits, metrics, domains };
+    actix_web::rt::spawn(refresh_newest_story(my_app_state.clone()));
+
+    let metrics_access = match MetricsAccess::from_env() {
+        Ok(access) => access,
+        Err(e) => {
+            tracing::error!("Invalid metrics configuration: {}", e);
+            return Ok(());
+        }
+    };
+
+    if let Some(metrics_addr_port) = metrics_access.bind {
+        let access = metrics_access.clone();
+        let exporter = exporter.clone();
+        let metrics_server = HttpServer::new(move || {
+            let access = access.clone();
+            let metrics_route = move |req: &dev::ServiceRequest| access.should_render(req);
+            App::new().wrap(RequestMetrics::new(
+                global::meter("actix_web"), Some(metrics_route), Some(exporter.clone())))
+        })
+            .workers(1)
+            .bind(metrics_addr_port)?
+            .run();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = metrics_server.await {
+                tracing::error!("Metrics server stopped: {}", e);
             }
-        };
+        });
+    }
 
+    let server = HttpServer::new(move || {
         use std::path::{Path};
 
         let static_directory = std::env::var("STATIC_DIRECTORY")
             .map(|v| Path::new(&v).to_owned())
             .map_or(None, |p| if p.is_dir() { Some(p) } else { None });
 
-        let config = match pg_url.parse() {
-            Ok(config) => config,
-            Err(e) => {
-                println!("Invalid POSTGRESQL_URL found");
-                panic!(e)
-            }
-        };
-
-        let manager_config = ManagerConfig { recycling_method: RecyclingMethod::Fast };
-        let manager = Manager::from_config(config, NoTls, manager_config);
-        let pool = Pool::new(manager, 30);
-
-        let auth_url = std::env::var("MINIAUTHURE_URL").ok();
-        if auth_url.is_none() {
-            panic!("No MINIAUTHURE_URL found")
-        }
-
-        let auth_url = String::from(auth_url.unwrap());
-        let auth_client = reqwest::ClientBuilder::new()
-            .danger_accept_invalid_certs(true)
-            .build().unwrap();
-        let my_app_state = AppState { pool, auth_url, auth_client };
-
         let json_cfg = web::JsonConfig::default()
-            .error_handler(|err, _req| {
-                let err_message = format!("{:?}", &err);
-                let bad_req = HttpResponse::BadRequest().body(err_message);
+            .error_handler(|err, req| {
+                let bad_req = WebError::Invalid(err.to_string()).to_response(req);
+                error::InternalError::from_response(err, bad_req).into()
+            });
+        // the query string's deserializer doesn't say which parameter was invalid, only why
+        let query_cfg = web::QueryConfig::default()
+            .error_handler(|err, req| {
+                let bad_req = WebError::Validation(String::from("query"), err.to_string()).to_response(req);
                 error::InternalError::from_response(err, bad_req).into()
             });
 
         let ranks = web::scope("/ranks")
             .service(set_story_ranking)
-            .service(get_story_ranking);
+            .service(get_flags)
+            .service(mark_read)
+            .service(snooze)
+            .service(clear_ranks)
+            .service(delete_ranks)
+            .service(get_rank_log)
+            .service(undo_ranks)
+            .service(get_story_ranking)
+            .service(get_story_count);
+        let authors = web::scope("/authors")
+            .service(get_author_stats);
+        let tags = web::scope("/tags")
+            .service(get_tags)
+            .service(add_tags)
+            .service(remove_tags)
+            .service(rename_tag)
+            .service(merge_tags);
+        let notes = web::scope("/notes")
+            .service(get_notes)
+            .service(add_note)
+            .service(edit_note)
+            .service(delete_note)
+            .service(get_note_history);
+        let stats = web::scope("/stats")
+            .service(get_daily_stats);
 
         // Request metrics middleware
-        let exporter = opentelemetry_prometheus::exporter().init();
-        let meter = global::meter("actix_web");
-        let metrics_route = |req: &dev::ServiceRequest| {
-            if req.path() != "/metrics" || req.method() != http::Method::GET {
-                return false;
-            }
-
-            let addr = match req.peer_addr() {
-                Some(addr) => addr,
-                None => { return false; }
-            };
-
-            let allowed: IpAddr = "127.0.0.1".parse().unwrap();
-            if addr.ip() != allowed {
-                return false
-            }
-
-            true
-        };
-        let request_metrics = RequestMetrics::new(meter, Some(metrics_route), Some(exporter));
+        let access = metrics_access.clone();
+        let metrics_route = move |req: &dev::ServiceRequest| access.should_render(req);
+        let request_metrics = RequestMetrics::new(
+            global::meter("actix_web"), Some(metrics_route), Some(exporter.clone()));
 
         let app = App::new()
+            .wrap_fn(|req, srv| {
+                let request_id = RequestId::from_service_request(&req);
+                let span = tracing::info_span!(
+                    "request", request_id = %request_id.0, method = %req.method(), path = %req.path());
+                req.extensions_mut().insert(request_id.clone());
+                let started = Instant::now();
+                let response = srv.call(req).instrument(span.clone());
+                async move {
+                    let mut response = response.await?;
+                    tracing::info!(
+                        status = response.status().as_u16(),
+                        elapsed_ms = started.elapsed().as_millis() as u64,
+                        "request complete");
+                    let (name, value) = request_id.header();
+                    response.headers_mut().insert(name, value);
+                    Ok(response)
+                }.instrument(span)
+            })
             .wrap(request_metrics.clone())
             .data(my_app_state.clone())
             .app_data(json_cfg)
+            .app_data(query_cfg)
             .service(ranks)
+            .service(authors)
+            .service(tags)
+            .service(notes)
+            .service(stats)
             .service(authenticate);
 
         if let Some(static_directory) = static_directory {
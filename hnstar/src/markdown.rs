@@ -0,0 +1,62 @@
+//! Notes are written in Markdown and shown as HTML, which is sanitized since notes are user input.
+
+use pulldown_cmark::{html, Options, Parser};
+
+/// Render Markdown to HTML without scripts, styles, event handlers or other unsafe markup; links open in a new
+/// page without passing the referrer
+pub fn to_safe_html(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let mut unsafe_html = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options));
+    ammonia::Builder::default()
+        .link_rel(Some("noopener noreferrer nofollow"))
+        .set_tag_attribute_value("a", "target", "_blank")
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_scripts() {
+        let html = to_safe_html("hello <script>alert(1)</script>\n\n<script>alert(2)</script>");
+        assert!(!html.contains("script"), "{}", html);
+        assert!(!html.contains("alert"), "{}", html);
+        assert!(html.contains("hello"), "{}", html);
+    }
+
+    #[test]
+    fn drops_event_attributes() {
+        let html = to_safe_html(r#"<img src="x.png" onerror="alert(1)"> <b onclick="alert(2)">bold</b>"#);
+        assert!(!html.contains("onerror"), "{}", html);
+        assert!(!html.contains("onclick"), "{}", html);
+        assert!(!html.contains("alert"), "{}", html);
+        assert!(html.contains("<b>bold</b>"), "{}", html);
+    }
+
+    #[test]
+    fn drops_javascript_links() {
+        for markdown in &["[click](javascript:alert(1))", r#"<a href="javascript:alert(1)">click</a>"#,
+                          "[click](JaVaScRiPt:alert(1))"] {
+            let html = to_safe_html(markdown);
+            assert!(!html.to_lowercase().contains("javascript"), "{}", html);
+            assert!(html.contains("click"), "{}", html);
+        }
+    }
+
+    #[test]
+    fn links_open_in_a_new_page_without_the_referrer() {
+        let html = to_safe_html(
+            r#"[hn](https://news.ycombinator.com) <a href="https://x.com" rel="opener" target="_self">x</a>"#);
+        assert_eq!(html.matches(r#"rel="noopener noreferrer nofollow""#).count(), 2, "{}", html);
+        assert_eq!(html.matches(r#"target="_blank""#).count(), 2, "{}", html);
+        assert!(!html.contains(r#"rel="opener""#), "{}", html);
+        assert!(!html.contains("_self"), "{}", html);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::aliases::PgPool;
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::{Counter, Meter, ValueObserver, ValueRecorder};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+/// Business and pool metrics exported next to the generic actix request metrics
+pub struct Metrics {
+    auth_latency: ValueRecorder<f64>,
+    query_latency: ValueRecorder<f64>,
+    query_rows: ValueRecorder<u64>,
+    ranks_written: Counter<u64>,
+    newest_story_timestamp: Arc<AtomicI64>,
+    _pool_size: ValueObserver<i64>,
+    _pool_waiters: ValueObserver<i64>,
+    _newest_story_age: ValueObserver<i64>,
+}
+
+impl Metrics {
+    pub fn new(meter: &Meter, pool: &PgPool) -> Self {
+        let size_pool = pool.clone();
+        let pool_size = meter
+            .i64_value_observer("hnstar_pool_size", move |result| {
+                let status = size_pool.status();
+                result.observe(status.size as i64, &[KeyValue::new("state", "open")]);
+                result.observe(status.available.max(0) as i64, &[KeyValue::new("state", "idle")]);
+            })
+            .with_description("Database connections in the pool")
+            .init();
+
+        let waiters_pool = pool.clone();
+        let pool_waiters = meter
+            .i64_value_observer("hnstar_pool_waiters", move |result| {
+                // deadpool reports callers waiting for a connection as negative availability
+                let status = waiters_pool.status();
+                result.observe((-status.available).max(0) as i64, &[]);
+            })
+            .with_description("Requests waiting for a database connection")
+            .init();
+
+        let newest_story_timestamp = Arc::new(AtomicI64::new(0));
+        let newest = newest_story_timestamp.clone();
+        let newest_story_age = meter
+            .i64_value_observer("hnstar_newest_story_age_seconds", move |result| {
+                let newest = newest.load(Ordering::Relaxed);
+                if newest > 0 {
+                    result.observe(chrono::Utc::now().timestamp() - newest, &[]);
+                }
+            })
+            .with_description("Seconds since the newest synchronized story was posted")
+            .init();
+
+        Metrics {
+            auth_latency: meter
+                .f64_value_recorder("hnstar_auth_duration_seconds")
+                .with_description("Authentication service round trip time")
+                .init(),
+            query_latency: meter
+                .f64_value_recorder("hnstar_query_duration_seconds")
+                .with_description("Story query time per combination of filters")
+                .init(),
+            query_rows: meter
+                .u64_value_recorder("hnstar_query_rows")
+                .with_description("Rows returned by story queries")
+                .init(),
+            ranks_written: meter
+                .u64_counter("hnstar_ranks_written_total")
+                .with_description("Ranking fields, tags and notes written, by endpoint and field")
+                .init(),
+            newest_story_timestamp,
+            _pool_size: pool_size,
+            _pool_waiters: pool_waiters,
+            _newest_story_age: newest_story_age,
+        }
+    }
+
+    pub fn record_auth(&self, elapsed: Duration, outcome: &'static str) {
+        self.auth_latency.record(elapsed.as_secs_f64(), &[KeyValue::new("outcome", outcome)]);
+    }
+
+    pub fn record_query(&self, elapsed: Duration, filters: String, rows: usize) {
+        self.query_latency.record(elapsed.as_secs_f64(), &[KeyValue::new("filters", filters)]);
+        self.query_rows.record(rows as u64, &[]);
+    }
+
+    /// Count a field written to `stories` rankings at once
+    pub fn record_rank_written(&self, endpoint: &'static str, field: &'static str, stories: usize) {
+        self.ranks_written.add(stories as u64, &[KeyValue::new("endpoint", endpoint), KeyValue::new("field", field)]);
+    }
+
+    pub fn set_newest_story_timestamp(&self, timestamp: i64) {
+        self.newest_story_timestamp.store(timestamp, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,254 @@
+use actix_web::{dev, http};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+/// An IPv4 or IPv6 CIDR range such as `10.0.0.0/8` or `::1/128`; a bare address is a single host
+#[derive(Clone, Debug)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    pub fn parse(range: &str) -> Result<Self, String> {
+        let range = range.trim();
+        let (address, prefix) = match range.find('/') {
+            Some(i) => (&range[..i], Some(&range[i + 1..])),
+            None => (range, None),
+        };
+
+        let network: IpAddr = address.parse()
+            .map_err(|_| format!("Invalid address in range {}", range))?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok()
+                .filter(|p| *p <= max_prefix)
+                .ok_or_else(|| format!("Invalid prefix length in range {}", range))?,
+            None => max_prefix,
+        };
+
+        // peers are matched as plain IPv4, so IPv4-mapped ranges are too
+        match canonical(network) {
+            IpAddr::V4(v4) if network.is_ipv6() && prefix >= 96 =>
+                Ok(IpRange { network: IpAddr::V4(v4), prefix: prefix - 96 }),
+            _ => Ok(IpRange { network, prefix }),
+        }
+    }
+
+    pub fn parse_list(ranges: &str) -> Result<Vec<Self>, String> {
+        ranges.split(',')
+            .filter(|r| !r.trim().is_empty())
+            .map(IpRange::parse)
+            .collect()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, canonical(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// Who may scrape /metrics and where it is served, configured with
+///
+/// - `METRICS_ALLOW`: comma separated CIDR ranges, defaulting to loopback (`127.0.0.1/32,::1/128`)
+/// - `METRICS_TOKEN`: when set, scrapers must also send `Authorization: Bearer <token>`
+/// - `METRICS_BIND_ADDR_PORT`: serve metrics only on this separate address instead of the main one
+/// - `TRUSTED_PROXIES`: CIDR ranges of reverse proxies whose `X-Forwarded-For` is believed
+#[derive(Clone)]
+pub struct MetricsAccess {
+    allowed: Vec<IpRange>,
+    trusted_proxies: Vec<IpRange>,
+    token: Option<String>,
+    pub bind: Option<SocketAddr>,
+}
+
+impl MetricsAccess {
+    pub fn from_env() -> Result<Self, String> {
+        let allowed = IpRange::parse_list(&std::env::var("METRICS_ALLOW")
+            .unwrap_or_else(|_| String::from("127.0.0.1/32,::1/128")))?;
+        let trusted_proxies = IpRange::parse_list(&std::env::var("TRUSTED_PROXIES").unwrap_or_default())?;
+        let token = std::env::var("METRICS_TOKEN").ok().filter(|t| !t.is_empty());
+        let bind = match std::env::var("METRICS_BIND_ADDR_PORT") {
+            Ok(bind) => Some(bind.to_socket_addrs().ok()
+                .and_then(|mut addrs| addrs.next())
+                .ok_or_else(|| format!("Invalid METRICS_BIND_ADDR_PORT {}", bind))?),
+            Err(_) => None,
+        };
+
+        Ok(MetricsAccess { allowed, trusted_proxies, token, bind })
+    }
+
+    /// Whether this is a metrics scrape that should be answered
+    pub fn should_render(&self, req: &dev::ServiceRequest) -> bool {
+        if req.path() != "/metrics" || req.method() != http::Method::GET {
+            return false;
+        }
+
+        if let Some(bind) = self.bind {
+            if req.app_config().local_addr() != bind {
+                return false;
+            }
+        }
+
+        let client = match self.client_ip(req) {
+            Some(client) => client,
+            None => { return false; }
+        };
+
+        if !self.allowed.iter().any(|range| range.contains(client)) {
+            return false;
+        }
+
+        match &self.token {
+            Some(token) => req.headers().get(http::header::AUTHORIZATION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())),
+            None => true,
+        }
+    }
+
+    /// The peer address, or when the peer is a trusted proxy, the nearest untrusted X-Forwarded-For hop
+    fn client_ip(&self, req: &dev::ServiceRequest) -> Option<IpAddr> {
+        let peer = canonical(req.peer_addr()?.ip());
+        if !self.is_trusted_proxy(peer) {
+            return Some(peer);
+        }
+
+        let forwarded: Vec<IpAddr> = req.headers().get_all("x-forwarded-for")
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .map(|hop| hop.trim().parse::<IpAddr>().map(canonical))
+            .collect::<Result<_, _>>()
+            .ok()?;
+
+        let mut client = peer;
+        for hop in forwarded.into_iter().rev() {
+            client = hop;
+            if !self.is_trusted_proxy(hop) {
+                break;
+            }
+        }
+
+        Some(client)
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn access(allowed: &str, trusted_proxies: &str, token: Option<&str>) -> MetricsAccess {
+        MetricsAccess {
+            allowed: IpRange::parse_list(allowed).unwrap(),
+            trusted_proxies: IpRange::parse_list(trusted_proxies).unwrap(),
+            token: token.map(String::from),
+            bind: None,
+        }
+    }
+
+    fn scrape(peer: &str) -> TestRequest {
+        TestRequest::get().uri("/metrics").peer_addr(format!("{}:4000", peer).parse().unwrap())
+    }
+
+    fn allows(access: &MetricsAccess, request: TestRequest) -> bool {
+        access.should_render(&request.to_srv_request())
+    }
+
+    #[test]
+    fn peers_inside_and_outside_the_ranges() {
+        let access = access("10.0.0.0/8,192.168.1.5", "", None);
+        assert!(allows(&access, scrape("10.1.2.3")));
+        assert!(allows(&access, scrape("192.168.1.5")));
+        assert!(!allows(&access, scrape("192.168.1.6")));
+        assert!(!allows(&access, scrape("11.0.0.1")));
+        assert!(!allows(&access, TestRequest::get().uri("/metrics")));
+        assert!(!allows(&access, TestRequest::get().uri("/other").peer_addr("10.1.2.3:4000".parse().unwrap())));
+    }
+
+    #[test]
+    fn loopback_by_default() {
+        let access = access("127.0.0.1/32,::1/128", "", None);
+        assert!(allows(&access, scrape("127.0.0.1")));
+        assert!(allows(&access, scrape("[::1]")));
+        assert!(!allows(&access, scrape("[::2]")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_and_ranges() {
+        let plain = access("10.0.0.0/8", "", None);
+        assert!(allows(&plain, scrape("[::ffff:10.1.2.3]")));
+        assert!(!allows(&plain, scrape("[::ffff:11.1.2.3]")));
+
+        let mapped = access("::ffff:10.0.0.0/104", "", None);
+        assert!(allows(&mapped, scrape("10.1.2.3")));
+        assert!(allows(&mapped, scrape("[::ffff:10.1.2.3]")));
+        assert!(!allows(&mapped, scrape("11.1.2.3")));
+    }
+
+    #[test]
+    fn forwarded_for_only_from_trusted_proxies() {
+        let access = access("10.0.0.0/8", "172.16.0.0/12", None);
+        let forwarded = |peer: &str, xff: &str| scrape(peer).insert_header(("x-forwarded-for", xff));
+
+        assert!(allows(&access, forwarded("172.16.0.1", "10.1.2.3")));
+        assert!(!allows(&access, forwarded("172.16.0.1", "8.8.8.8")));
+        // the nearest untrusted hop is the client, not whatever it claims came before it
+        assert!(!allows(&access, forwarded("172.16.0.1", "10.1.2.3, 8.8.8.8")));
+        assert!(allows(&access, forwarded("172.16.0.1", "8.8.8.8, 10.1.2.3, 172.16.0.2")));
+        assert!(!allows(&access, forwarded("172.16.0.1", "not an address")));
+
+        // a spoofed header from an untrusted peer is ignored
+        assert!(!allows(&access, forwarded("8.8.8.8", "10.1.2.3")));
+        assert!(allows(&access, forwarded("10.1.2.3", "8.8.8.8")));
+    }
+
+    #[test]
+    fn tokens() {
+        let access = access("10.0.0.0/8", "", Some("secret"));
+        let with = |authorization: &str| scrape("10.1.2.3").insert_header((http::header::AUTHORIZATION, authorization));
+
+        assert!(allows(&access, with("Bearer secret")));
+        assert!(!allows(&access, with("Bearer wrong")));
+        assert!(!allows(&access, with("Bearer secrets")));
+        assert!(!allows(&access, with("secret")));
+        assert!(!allows(&access, scrape("10.1.2.3")));
+        assert!(!allows(&access, scrape("11.1.2.3").insert_header((http::header::AUTHORIZATION, "Bearer secret"))));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert!(IpRange::parse("10.0.0.0/33").is_err());
+        assert!(IpRange::parse("::1/129").is_err());
+        assert!(IpRange::parse("example.com").is_err());
+        assert_eq!(IpRange::parse_list(" 10.0.0.0/8, ,::1 ").unwrap().len(), 2);
+    }
+}
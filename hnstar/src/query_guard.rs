@@ -0,0 +1,256 @@
+//! Limits on what a story query built from user input can cost the database.
+
+use crate::aliases::PooledConn;
+use crate::error_util::WebError;
+use deadpool::managed::Object;
+use regex_syntax::hir::{Hir, HirKind, RepetitionKind, RepetitionRange};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{CancelToken, NoTls};
+
+pub const MAX_REGEX_LENGTH: usize = 200;
+const MAX_REGEX_NESTING: u32 = 20;
+
+/// Time limits of story queries, configured with `SLOW_QUERY_MS` and `QUERY_TIMEOUT_MS`
+#[derive(Copy, Clone)]
+pub struct QueryLimits {
+    /// Queries taking longer are logged
+    pub slow: Duration,
+    /// Queries taking longer are cancelled by Postgres
+    pub timeout: Duration,
+}
+
+impl QueryLimits {
+    pub fn from_env() -> Self {
+        let millis = |name: &str, default: u64| std::env::var(name).ok()
+            .and_then(|ms| ms.parse().ok())
+            .map_or(Duration::from_millis(default), Duration::from_millis);
+        QueryLimits {
+            slow: millis("SLOW_QUERY_MS", 500),
+            timeout: millis("QUERY_TIMEOUT_MS", 5000),
+        }
+    }
+}
+
+/// Check a regular expression before Postgres runs it against every story in the window.
+///
+/// The accepted patterns are those of Rust's regex syntax, with Postgres' word boundary escapes `\m`, `\M`, `\y`
+/// and `\Y` and its `\Z` end of text. That leaves out Postgres' expensive extras, backreferences and lookaround.
+/// Postgres compiles the pattern again when it runs, and its rejections of what Rust accepts, such as `\p{L}`,
+/// become validation errors through `query_error`.
+pub fn validate_regex(field: &str, pattern: &str) -> Result<(), WebError> {
+    let reject = |message: String| WebError::Validation(String::from(field), message);
+    if pattern.chars().count() > MAX_REGEX_LENGTH {
+        return Err(reject(format!("Regular expression is longer than {} characters", MAX_REGEX_LENGTH)));
+    }
+
+    let hir = regex_syntax::ParserBuilder::new()
+        .nest_limit(MAX_REGEX_NESTING)
+        .build()
+        .parse(&postgres_escapes_to_rust(pattern))
+        .map_err(|e| reject(match e {
+            regex_syntax::Error::Parse(e) => format!(
+                "Invalid regular expression at column {}: {}", e.span().start.column, e.kind()),
+            regex_syntax::Error::Translate(e) => format!(
+                "Invalid regular expression at column {}: {}", e.span().start.column, e.kind()),
+            _ => String::from("Invalid regular expression"),
+        }))?;
+
+    if has_nested_repetition(&hir, false) {
+        return Err(reject(String::from(
+            "Regular expression repeats a repetition, like (a+)+, which can take exponential time")));
+    }
+
+    Ok(())
+}
+
+/// The pattern with Postgres' escapes Rust has no syntax for replaced by Rust's equivalents of the same length, so
+/// error columns still point into the original
+fn postgres_escapes_to_rust(pattern: &str) -> String {
+    let mut rust = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            rust.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('m') | Some('M') | Some('y') => rust.push_str("\\b"),
+            Some('Y') => rust.push_str("\\B"),
+            Some('Z') => rust.push_str("\\z"),
+            Some(escaped) => {
+                rust.push(c);
+                rust.push(escaped);
+            }
+            None => rust.push(c),
+        }
+    }
+
+    rust
+}
+
+fn is_unbounded(kind: &RepetitionKind) -> bool {
+    matches!(kind, RepetitionKind::ZeroOrMore | RepetitionKind::OneOrMore
+        | RepetitionKind::Range(RepetitionRange::AtLeast(_)))
+}
+
+fn repeats(kind: &RepetitionKind) -> bool {
+    !matches!(kind, RepetitionKind::ZeroOrOne
+        | RepetitionKind::Range(RepetitionRange::Exactly(1))
+        | RepetitionKind::Range(RepetitionRange::Bounded(_, 1)))
+}
+
+fn has_nested_repetition(hir: &Hir, in_unbounded: bool) -> bool {
+    match hir.kind() {
+        HirKind::Repetition(repetition) => (in_unbounded && repeats(&repetition.kind))
+            || has_nested_repetition(&repetition.hir, in_unbounded || is_unbounded(&repetition.kind)),
+        HirKind::Group(group) => has_nested_repetition(&group.hir, in_unbounded),
+        HirKind::Concat(hirs) | HirKind::Alternation(hirs) => hirs.iter()
+            .any(|hir| has_nested_repetition(hir, in_unbounded)),
+        _ => false,
+    }
+}
+
+/// A pooled connection that is closed instead of returned to the pool when dropped during a query, since the cancel
+/// request `CancelOnDrop` sent may arrive late and would cancel whatever the connection runs next
+pub struct GuardedConn {
+    conn: Option<PooledConn>,
+    querying: Arc<AtomicBool>,
+}
+
+impl GuardedConn {
+    pub fn new(conn: PooledConn) -> Self {
+        GuardedConn { conn: Some(conn), querying: Arc::new(AtomicBool::new(false)) }
+    }
+}
+
+impl Deref for GuardedConn {
+    type Target = PooledConn;
+
+    fn deref(&self) -> &PooledConn {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for GuardedConn {
+    fn deref_mut(&mut self) -> &mut PooledConn {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for GuardedConn {
+    fn drop(&mut self) {
+        if let (true, Some(conn)) = (self.querying.load(Ordering::SeqCst), self.conn.take()) {
+            tracing::info!("closing connection of a cancelled query");
+            drop(Object::take(conn));
+        }
+    }
+}
+
+/// Cancels the running query when dropped before being disarmed, as happens to a handler's future when the
+/// client disconnects; otherwise Postgres would keep working on a result nobody will read
+pub struct CancelOnDrop {
+    token: Option<CancelToken>,
+    querying: Arc<AtomicBool>,
+}
+
+impl CancelOnDrop {
+    /// Arm before the query starts, so the connection is closed if it is dropped at any point until disarmed
+    pub fn new(conn: &GuardedConn) -> Self {
+        conn.querying.store(true, Ordering::SeqCst);
+        CancelOnDrop { token: Some(conn.cancel_token()), querying: conn.querying.clone() }
+    }
+
+    pub fn disarm(mut self) {
+        self.token = None;
+        self.querying.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            tracing::info!("client went away, cancelling query");
+            actix_web::rt::spawn(async move {
+                if let Err(e) = token.cancel_query(NoTls).await {
+                    tracing::warn!(error = %e, "failed to cancel query");
+                }
+            });
+        }
+    }
+}
+
+/// Errors caused by the filter itself are the client's to fix, the rest are internal
+pub fn query_error(error: tokio_postgres::Error, limits: QueryLimits) -> WebError {
+    match error.code() {
+        Some(code) if *code == SqlState::QUERY_CANCELED => WebError::QueryTimeout(limits.timeout),
+        Some(code) if *code == SqlState::INVALID_REGULAR_EXPRESSION => WebError::Validation(
+            String::from("regex"),
+            format!("Invalid regular expression: {}", error.as_db_error().map_or("", |e| e.message()))),
+        _ => WebError::Tokio(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(pattern: &str) -> Result<(), String> {
+        validate_regex("title.regex", pattern).map_err(|e| match e {
+            WebError::Validation(field, message) => {
+                assert_eq!(field, "title.regex");
+                message
+            }
+            e => panic!("unexpected error {:?}", e),
+        })
+    }
+
+    #[test]
+    fn accepts_common_patterns() {
+        for pattern in &["^Show HN", "rust|golang", "(?i)launch(es|ed)?", "[[:digit:]]{4}", "v[0-9]+\\.[0-9]+"] {
+            assert_eq!(validate(pattern), Ok(()), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn accepts_postgres_escapes() {
+        for pattern in &["\\mrust\\M", "\\ygo\\y", "\\Yx", "done\\Z", "\\\\m"] {
+            assert_eq!(validate(pattern), Ok(()), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn rejects_backreferences_and_lookaround() {
+        assert!(validate("(a)\\1").is_err());
+        assert!(validate("rust(?=lang)").is_err());
+        assert!(validate("(?<!un)safe").is_err());
+    }
+
+    #[test]
+    fn rejects_nested_repetition() {
+        assert!(validate("(a+)+").unwrap_err().contains("exponential"));
+        assert!(validate("(a*b?)*").unwrap_err().contains("exponential"));
+        assert_eq!(validate("(ab)+c?"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_long_and_deep_patterns() {
+        assert!(validate(&"a".repeat(MAX_REGEX_LENGTH + 1)).unwrap_err().contains("longer than"));
+        let deep = format!("{}a{}", "(".repeat(30), ")".repeat(30));
+        assert!(validate(&deep).is_err());
+    }
+
+    #[test]
+    fn errors_point_into_the_original_pattern() {
+        assert_eq!(validate("\\mrust(").unwrap_err(), "Invalid regular expression at column 7: unclosed group");
+    }
+
+    #[test]
+    fn escapes_keep_their_length() {
+        assert_eq!(postgres_escapes_to_rust("\\mx\\M\\\\m"), "\\bx\\b\\\\m");
+    }
+}
@@ -0,0 +1,519 @@
+//! A small query language for story filters, e.g. `title:rust score>200 -domain:medium.com`.
+//!
+//! Terms next to each other are AND'd, `OR` and parentheses group them and `-` or `NOT` negates.
+//! Bare words and quoted phrases match titles, and `note:` searches the user's notes like the `notes` filter.
+//! Ages take a unit of m, h, d or w: `age<3d`, and count from the query's `asOf`. A word before a colon that is
+//! not a field, as in `Rust: 2024`, is searched for in titles like any other word.
+
+use crate::domain::DomainNormalizer;
+use crate::error_util::WebError;
+use crate::sql_builder::{Comparison, Parameters, Placeholder, Predicate};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextField {
+    Title,
+    Url,
+    Domain,
+    Comment,
+    /// Full-text search of the user's notes, as the `notes` filter
+    Note,
+    /// The poster's username, matched whole
+    Author,
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NumberField {
+    Score,
+    Comments,
+    Stars,
+    Status,
+    Flags,
+    /// Seconds since the story was posted
+    Age,
+}
+
+/// A boolean combination of story filters, parsed from the query language or sent as JSON
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum FilterExpr {
+    And { terms: Vec<FilterExpr> },
+    Or { terms: Vec<FilterExpr> },
+    Not { term: Box<FilterExpr> },
+    /// Case-insensitive substring match, or for domains the normalized domain
+    Text { field: TextField, value: String },
+    Compare { field: NumberField, cmp: Comparison, value: i64 },
+}
+
+impl FilterExpr {
+    /// Whether the expression compares ages, which depend on the time of the query
+    pub fn has_ages(&self) -> bool {
+        match self {
+            FilterExpr::And { terms } | FilterExpr::Or { terms } => terms.iter().any(FilterExpr::has_ages),
+            FilterExpr::Not { term } => term.has_ages(),
+            FilterExpr::Compare { field: NumberField::Age, .. } => true,
+            FilterExpr::Text { .. } | FilterExpr::Compare { .. } => false,
+        }
+    }
+
+    /// SQL predicate over the story query's `s` and `r` aliases for the user bound as `user`, binding values as
+    /// parameters, with ages counted back from `now`
+    pub fn to_sql(&self, parameters: &mut Parameters, user: Placeholder, now: i64,
+                  domains: &DomainNormalizer) -> Result<Predicate, WebError> {
+        Ok(match self {
+            FilterExpr::And { terms } => Predicate::All(terms_sql(terms, parameters, user, now, domains)?),
+            FilterExpr::Or { terms } => Predicate::Any(terms_sql(terms, parameters, user, now, domains)?),
+            FilterExpr::Not { term } => term.to_sql(parameters, user, now, domains)?.negate(),
+            FilterExpr::Text { field: TextField::Domain, value } =>
+                crate::domain_predicate(parameters.bind(vec![crate::normalize_domain(domains, value)])),
+            FilterExpr::Text { field: TextField::Note, value } =>
+                crate::notes_predicate(user, parameters.bind(value.clone())),
+            FilterExpr::Text { field, value } => {
+                let p = parameters.bind(value.to_lowercase());
+                Predicate::sql(match field {
+                    TextField::Title => format!("strpos(lower(s.title), {}) > 0", p),
+                    TextField::Url => format!("strpos(lower(s.url), {}) > 0", p),
+                    TextField::Comment => format!("strpos(lower(coalesce(r.comment, '')), {}) > 0", p),
+                    TextField::Author => format!("lower(s.by) = {}", p),
+                    TextField::Domain | TextField::Note => unreachable!(),
+                })
+            }
+            FilterExpr::Compare { field: NumberField::Age, cmp, value } => {
+                // Younger than an age is posted after that long ago, which can use the timestamp index
+                Predicate::compare("s.timestamp", cmp.flipped(), parameters.bind(now - value))
+            }
+            FilterExpr::Compare { field, cmp, value } => {
+                let value = i32::try_from(*value).map_err(|_| WebError::Validation(
+                    String::from("q"), format!("{} is out of range", value)))?;
+                let column = match field {
+                    NumberField::Score => "s.score",
+                    NumberField::Comments => "s.descendants",
+                    NumberField::Stars => "coalesce(r.stars, 0)",
+                    NumberField::Status => "s.status",
+                    NumberField::Flags => "coalesce(r.flags, 0)",
+                    NumberField::Age => unreachable!(),
+                };
+                Predicate::compare(column, *cmp, parameters.bind(value))
+            }
+        })
+    }
+}
+
+fn terms_sql(terms: &[FilterExpr], parameters: &mut Parameters, user: Placeholder, now: i64,
+             domains: &DomainNormalizer) -> Result<Vec<Predicate>, WebError> {
+    terms.iter()
+        .map(|term| term.to_sql(parameters, user, now, domains))
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum TokenKind {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Term(FilterExpr),
+}
+
+struct Token {
+    kind: TokenKind,
+    text: String,
+    /// 1-based character position of the token in the query
+    column: usize,
+}
+
+fn parse_error(message: String) -> WebError {
+    WebError::Validation(String::from("q"), message)
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c == '(' || c == ')'
+}
+
+fn lex(query: &str) -> Result<Vec<Token>, WebError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c == '(' || c == ')' {
+            let kind = if c == '(' { TokenKind::LParen } else { TokenKind::RParen };
+            tokens.push(Token { kind, text: c.to_string(), column });
+            i += 1;
+            continue;
+        }
+
+        // a dash before a term or group negates it, while one on its own is a word
+        if c == '-' && i + 1 < chars.len() && !chars[i + 1].is_whitespace() && chars[i + 1] != ')' {
+            tokens.push(Token { kind: TokenKind::Not, text: String::from("-"), column });
+            i += 1;
+            continue;
+        }
+
+        // A field name followed by an operator starts a field term, while other words before a colon are text
+        let name_end = (i..chars.len()).find(|&j| !chars[j].is_ascii_alphabetic()).unwrap_or(chars.len());
+        let field: String = chars[i..name_end].iter().collect();
+        let operator = ["<=", ">=", ":", "<", ">", "="].iter()
+            .find(|op| chars[name_end..].iter().take(op.len()).copied().eq(op.chars()))
+            .filter(|op| **op != ":" || text_field(&field).is_some() || number_field(&field).is_some());
+        if let (true, Some(operator)) = (name_end > i, operator) {
+            let value_start = name_end + operator.len();
+            let (value, end) = read_value(&chars, value_start)?;
+            let text: String = chars[i..end].iter().collect();
+            if value.is_empty() {
+                return Err(parse_error(format!("Missing value after '{}' at column {}", text, column)));
+            }
+
+            let term = field_term(&field, operator, &value, column, value_start + 1)?;
+            tokens.push(Token { kind: TokenKind::Term(term), text, column });
+            i = end;
+            continue;
+        }
+
+        let (value, end) = read_value(&chars, i)?;
+        let text: String = chars[i..end].iter().collect();
+        let kind = match text.as_str() {
+            "AND" => TokenKind::And,
+            "OR" => TokenKind::Or,
+            "NOT" => TokenKind::Not,
+            _ => TokenKind::Term(FilterExpr::Text { field: TextField::Title, value }),
+        };
+        tokens.push(Token { kind, text, column });
+        i = end;
+    }
+
+    Ok(tokens)
+}
+
+/// A quoted phrase or a bare word, returning it and the index after it
+fn read_value(chars: &[char], start: usize) -> Result<(String, usize), WebError> {
+    if chars.get(start) == Some(&'"') {
+        let close = (start + 1..chars.len()).find(|&j| chars[j] == '"')
+            .ok_or_else(|| parse_error(format!("Unclosed quote at column {}", start + 1)))?;
+        return Ok((chars[start + 1..close].iter().collect(), close + 1));
+    }
+
+    let end = (start..chars.len()).find(|&j| is_delimiter(chars[j])).unwrap_or(chars.len());
+    Ok((chars[start..end].iter().collect(), end))
+}
+
+fn text_field(name: &str) -> Option<TextField> {
+    match name.to_lowercase().as_str() {
+        "title" => Some(TextField::Title),
+        "url" => Some(TextField::Url),
+        "domain" | "site" => Some(TextField::Domain),
+        "comment" => Some(TextField::Comment),
+        "note" | "notes" => Some(TextField::Note),
+        "by" | "author" => Some(TextField::Author),
+        _ => None,
+    }
+}
+
+fn number_field(name: &str) -> Option<NumberField> {
+    match name.to_lowercase().as_str() {
+        "score" | "points" => Some(NumberField::Score),
+        "comments" => Some(NumberField::Comments),
+        "stars" => Some(NumberField::Stars),
+        "status" => Some(NumberField::Status),
+        "flags" => Some(NumberField::Flags),
+        "age" => Some(NumberField::Age),
+        _ => None,
+    }
+}
+
+fn field_term(field: &str, operator: &str, value: &str, column: usize, value_column: usize) -> Result<FilterExpr, WebError> {
+    if let Some(field) = text_field(field) {
+        if operator != ":" {
+            return Err(parse_error(format!(
+                "'{}' can only be matched with ':', not '{}' at column {}", field_name(field), operator, column)));
+        }
+
+        return Ok(FilterExpr::Text { field, value: String::from(value) });
+    }
+
+    let field = number_field(field).ok_or_else(|| parse_error(format!(
+        "Unknown field '{}' at column {}, quote the term to search titles for it", field, column)))?;
+
+    let cmp = match operator {
+        ":" | "=" => Comparison::Eq,
+        ">" => Comparison::Gt,
+        ">=" => Comparison::Ge,
+        "<" => Comparison::Lt,
+        _ => Comparison::Le,
+    };
+
+    let parsed = if field == NumberField::Age {
+        parse_age(value)
+    } else {
+        value.parse::<i64>().ok()
+    };
+    let value = parsed.ok_or_else(|| parse_error(match field {
+        NumberField::Age => format!("Invalid age '{}' at column {}, expected e.g. 12h or 3d", value, value_column),
+        _ => format!("Invalid number '{}' at column {}", value, value_column),
+    }))?;
+
+    Ok(FilterExpr::Compare { field, cmp, value })
+}
+
+fn field_name(field: TextField) -> &'static str {
+    match field {
+        TextField::Title => "title",
+        TextField::Url => "url",
+        TextField::Domain => "domain",
+        TextField::Comment => "comment",
+        TextField::Note => "note",
+        TextField::Author => "by",
+    }
+}
+
+/// An age like `90m`, `12h`, `3d` or `2w` in seconds
+fn parse_age(age: &str) -> Option<i64> {
+    let unit = age.chars().last()?;
+    let seconds = match unit {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => { return None; }
+    };
+    let amount: i64 = age[..age.len() - 1].parse().ok().filter(|a| *a >= 0)?;
+    amount.checked_mul(seconds)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    fn or_expr(&mut self) -> Result<FilterExpr, WebError> {
+        let mut terms = vec![self.and_expr()?];
+        while let Some(Token { kind: TokenKind::Or, .. }) = self.peek() {
+            self.next();
+            terms.push(self.and_expr()?);
+        }
+
+        Ok(if terms.len() == 1 { terms.pop().unwrap() } else { FilterExpr::Or { terms } })
+    }
+
+    fn and_expr(&mut self) -> Result<FilterExpr, WebError> {
+        let mut terms = vec![self.unary()?];
+        loop {
+            match self.peek().map(|t| &t.kind) {
+                Some(TokenKind::And) => {
+                    self.next();
+                    terms.push(self.unary()?);
+                }
+                Some(TokenKind::Or) | Some(TokenKind::RParen) | None => break,
+                Some(_) => terms.push(self.unary()?),
+            }
+        }
+
+        Ok(if terms.len() == 1 { terms.pop().unwrap() } else { FilterExpr::And { terms } })
+    }
+
+    fn unary(&mut self) -> Result<FilterExpr, WebError> {
+        let token = match self.next() {
+            Some(token) => token,
+            None => { return Err(parse_error(String::from("Expected a term at the end of the query"))); }
+        };
+
+        match &token.kind {
+            TokenKind::Not => Ok(FilterExpr::Not { term: Box::new(self.unary()?) }),
+            TokenKind::LParen => {
+                let column = token.column;
+                let expr = self.or_expr()?;
+                match self.next() {
+                    Some(Token { kind: TokenKind::RParen, .. }) => Ok(expr),
+                    _ => Err(parse_error(format!("Expected ')' to close '(' at column {}", column))),
+                }
+            }
+            TokenKind::Term(term) => Ok(term.clone()),
+            _ => Err(parse_error(format!("Unexpected '{}' at column {}", token.text, token.column))),
+        }
+    }
+}
+
+/// Parse the query language into a filter expression
+pub fn parse(query: &str) -> Result<FilterExpr, WebError> {
+    let tokens = lex(query)?;
+    if tokens.is_empty() {
+        return Ok(FilterExpr::And { terms: vec![] });
+    }
+
+    let mut parser = Parser { tokens, position: 0 };
+    let expr = parser.or_expr()?;
+    match parser.peek() {
+        Some(token) => Err(parse_error(format!("Unexpected '{}' at column {}", token.text, token.column))),
+        None => Ok(expr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title(value: &str) -> FilterExpr {
+        FilterExpr::Text { field: TextField::Title, value: String::from(value) }
+    }
+
+    fn error(query: &str) -> String {
+        match parse(query) {
+            Err(WebError::Validation(field, message)) => {
+                assert_eq!(field, "q");
+                message
+            }
+            Err(err) => panic!("unexpected error {:?}", err),
+            Ok(expr) => panic!("parsed {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn adjacent_terms_bind_tighter_than_or() {
+        assert_eq!(parse("a OR b c").unwrap(), FilterExpr::Or {
+            terms: vec![title("a"), FilterExpr::And { terms: vec![title("b"), title("c")] }],
+        });
+        assert_eq!(parse("a AND b OR c").unwrap(), FilterExpr::Or {
+            terms: vec![FilterExpr::And { terms: vec![title("a"), title("b")] }, title("c")],
+        });
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(parse("(a OR b) c").unwrap(), FilterExpr::And {
+            terms: vec![FilterExpr::Or { terms: vec![title("a"), title("b")] }, title("c")],
+        });
+        assert_eq!(parse("((a))").unwrap(), title("a"));
+    }
+
+    #[test]
+    fn negation() {
+        let domain = FilterExpr::Text { field: TextField::Domain, value: String::from("medium.com") };
+        assert_eq!(parse("-domain:medium.com").unwrap(), FilterExpr::Not { term: Box::new(domain.clone()) });
+        assert_eq!(parse("NOT domain:medium.com").unwrap(), FilterExpr::Not { term: Box::new(domain) });
+        assert_eq!(parse("-(a OR b)").unwrap(), FilterExpr::Not {
+            term: Box::new(FilterExpr::Or { terms: vec![title("a"), title("b")] }),
+        });
+        assert_eq!(parse("a - b").unwrap(), FilterExpr::And { terms: vec![title("a"), title("-"), title("b")] });
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(parse("title:\"rust async\"").unwrap(), title("rust async"));
+        assert_eq!(parse("site:example.com").unwrap(),
+                   FilterExpr::Text { field: TextField::Domain, value: String::from("example.com") });
+        assert_eq!(parse("note:todo").unwrap(),
+                   FilterExpr::Text { field: TextField::Note, value: String::from("todo") });
+        assert_eq!(parse("points>=200").unwrap(),
+                   FilterExpr::Compare { field: NumberField::Score, cmp: Comparison::Ge, value: 200 });
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse("age<3d").unwrap(),
+                   FilterExpr::Compare { field: NumberField::Age, cmp: Comparison::Lt, value: 3 * 24 * 60 * 60 });
+        assert_eq!(parse("age>=90m").unwrap(),
+                   FilterExpr::Compare { field: NumberField::Age, cmp: Comparison::Ge, value: 90 * 60 });
+        assert_eq!(parse("age>2w").unwrap(),
+                   FilterExpr::Compare { field: NumberField::Age, cmp: Comparison::Gt, value: 2 * 7 * 24 * 60 * 60 });
+        assert_eq!(error("age<3y"), "Invalid age '3y' at column 5, expected e.g. 12h or 3d");
+        assert_eq!(error("age<-3d"), "Invalid age '-3d' at column 5, expected e.g. 12h or 3d");
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        assert_eq!(error("title:rust )"), "Unexpected ')' at column 12");
+        assert_eq!(error("rust score>abc"), "Invalid number 'abc' at column 12");
+        assert_eq!(error("a (b OR c"), "Expected ')' to close '(' at column 3");
+        assert_eq!(error("a foo>3"), "Unknown field 'foo' at column 3, quote the term to search titles for it");
+        assert_eq!(error("title:\"rust"), "Unclosed quote at column 7");
+        assert_eq!(error("url>x"), "'url' can only be matched with ':', not '>' at column 1");
+        assert_eq!(error("score:"), "Missing value after 'score:' at column 1");
+        assert_eq!(error("a OR"), "Expected a term at the end of the query");
+        assert_eq!(error("a OR OR b"), "Unexpected 'OR' at column 6");
+    }
+
+    #[test]
+    fn words_before_colons_that_are_not_fields_are_text() {
+        assert_eq!(parse("Rust: 2024").unwrap(), FilterExpr::And { terms: vec![title("Rust:"), title("2024")] });
+        assert_eq!(parse("foo:bar").unwrap(), title("foo:bar"));
+        assert_eq!(parse("\"foo>3\"").unwrap(), title("foo>3"));
+        assert_eq!(parse("Title:rust").unwrap(), title("rust"));
+    }
+
+    #[test]
+    fn ages_count_from_the_query_time() {
+        assert!(parse("a (b OR -age<3d)").unwrap().has_ages());
+        assert!(!parse("a score>3").unwrap().has_ages());
+
+        let mut parameters = Parameters::new();
+        let user = parameters.bind(1);
+        parse("age<3d").unwrap().to_sql(&mut parameters, user, 1_000_000, &DomainNormalizer::new(None, vec![])).unwrap();
+        match &parameters.into_vec()[1] {
+            crate::sql_builder::SqlParameter::BigInt(since) => assert_eq!(*since, 1_000_000 - 3 * 24 * 60 * 60),
+            other => panic!("bound {:?}", other),
+        }
+    }
+
+    #[test]
+    fn domains_are_normalized_like_synced_stories() {
+        let list = "// ===BEGIN ICANN DOMAINS===\ncom\nuk\nco.uk\n// ===END ICANN DOMAINS===\n";
+        let domains = DomainNormalizer::new(Some(list.parse().unwrap()), vec![String::from("github.com")]);
+        for (query, domain) in [("domain:news.bbc.co.uk", "bbc.co.uk"), ("site:https://www.BBC.co.uk/news", "bbc.co.uk"),
+                                ("domain:github.com/User", "github.com/user")].iter() {
+            let mut parameters = Parameters::new();
+            let user = parameters.bind(1);
+            parse(query).unwrap().to_sql(&mut parameters, user, 0, &domains).unwrap();
+            match &parameters.into_vec()[1] {
+                crate::sql_builder::SqlParameter::VarcharArray(bound) => assert_eq!(bound, &vec![String::from(*domain)]),
+                other => panic!("bound {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert_eq!(parse("  ").unwrap(), FilterExpr::And { terms: vec![] });
+    }
+
+    fn sql(expr: &FilterExpr) -> String {
+        let mut parameters = Parameters::new();
+        let user = parameters.bind(1);
+        expr.to_sql(&mut parameters, user, 1_000_000, &DomainNormalizer::new(None, vec![])).unwrap().to_sql()
+    }
+
+    #[test]
+    fn empty_groups() {
+        assert_eq!(sql(&FilterExpr::And { terms: vec![] }), "true");
+        assert_eq!(sql(&FilterExpr::Or { terms: vec![] }), "false");
+    }
+
+    #[test]
+    fn sql_of_terms() {
+        assert_eq!(sql(&parse("-title:rust OR score>200").unwrap()),
+                   "(not coalesce(strpos(lower(s.title), $2) > 0, false) or s.score > $3)");
+        assert_eq!(sql(&parse("age<3d").unwrap()), "s.timestamp > $2");
+        let note = sql(&parse("note:todo").unwrap());
+        assert!(note.contains("from hnstar.story_note n"), "{}", note);
+        assert!(note.contains("n.user_main_id = $1 and n.search @@ websearch_to_tsquery('english', $2)"), "{}", note);
+    }
+}
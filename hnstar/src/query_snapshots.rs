@@ -0,0 +1,95 @@
+//! Snapshots of the SQL `get_query` builds for a range of filters, so changes to the generated queries show up in
+//! review as diffs of `src/snapshots`.
+//!
+//! After an intended change, regenerate the snapshots with `UPDATE_SNAPSHOTS=1 cargo test query_snapshots` and
+//! commit them with the change.
+
+use super::*;
+use std::path::PathBuf;
+
+/// Filters to snapshot, by snapshot name
+const CASES: &[(&str, &str)] = &[
+    ("default", r#"{}"#),
+    ("page_number", r#"{"timestamp": {"gt": 1000, "lt": 90000}, "pageSize": 5, "pageNumber": 2}"#),
+    ("timestamp_lt", r#"{"timestamp": {"lt": 90000}}"#),
+    ("search_relevance", r#"{"search": "rust", "sort": [{"sort": "relevance", "asc": false}]}"#),
+    ("regexes", r#"{"title": {"regex": "rust", "not": false}, "url": {"regex": "github", "not": true},
+        "comment": {"regex": "x", "not": false}}"#),
+    ("numbers", r#"{"score": {"gt": 1, "lt": 5}, "stars": {"gt": 1, "lt": 3}, "status": 1, "flags": 2}"#),
+    ("named_flags_status_in", r#"{"flags": {"any": ["saved"], "none": ["hidden", "read"]}, "status": {"in": [0, 1]}}"#),
+    ("z_score_log", r#"{"zScore": {"gt": -1, "lt": 1}, "percentile": {"gt": 10, "lt": 90}, "zScoreScale": "log"}"#),
+    ("normalization_window", r#"{"timestamp": {"gt": 1000, "lt": 90000}, "zScore": {"gt": 1}}"#),
+    ("normalization_day", r#"{"normalization": "day", "zScore": {"gt": 1}}"#),
+    ("normalization_rolling", r#"{"normalization": "rolling24h", "percentile": {"gt": 50}}"#),
+    ("domain_by", r#"{"domain": {"include": ["a.com"], "exclude": ["b.com"]}, "by": {"include": ["x"], "exclude": ["y"]}}"#),
+    ("query_language", r#"{"q": "title:rust score>200 -domain:medium.com (by:pg OR comments>=10) age<3d note:later",
+        "terms": {"op": "not", "term": {"op": "text", "field": "url", "value": "x"}}}"#),
+    ("sort_hot_velocity_by", r#"{"sort": [{"sort": "hot", "asc": false}, {"sort": "velocity", "asc": true},
+        {"sort": "by", "asc": true}], "asOf": 100000, "gravity": 1.5}"#),
+    ("sort_many", r#"{"sort": [{"sort": "zScore", "asc": true}, {"sort": "commentsPerPoint", "asc": false},
+        {"sort": "comments", "asc": true}, {"sort": "stars", "asc": false}]}"#),
+    // {"s":["score desc"],"k":[100],"id":5}
+    ("cursor_after", r#"{"sort": [{"sort": "score", "asc": false}],
+        "cursor": "eyJzIjpbInNjb3JlIGRlc2MiXSwiayI6WzEwMF0sImlkIjo1fQ"}"#),
+    // {"s":["hot desc"],"k":[1.5],"id":5,"b":true,"t":1000,"g":1}
+    ("cursor_before_hot", r#"{"sort": [{"sort": "hot", "asc": false}], "search": "go",
+        "cursor": "eyJzIjpbImhvdCBkZXNjIl0sImsiOlsxLjVdLCJpZCI6NSwiYiI6dHJ1ZSwidCI6MTAwMCwiZyI6MX0"}"#),
+    ("count_exact", r#"{"count": "exact", "search": "rust", "score": {"gt": 10}}"#),
+    ("count_estimate", r#"{"count": "estimate", "normalization": "day", "zScore": {"gt": 1}}"#),
+    ("read", r#"{"read": true}"#),
+    ("unread_new_since_last_visit", r#"{"read": false, "newSinceLastVisit": true}"#),
+    ("snoozed_include", r#"{"snoozed": "include"}"#),
+    ("snoozed_active", r#"{"snoozed": "active"}"#),
+    ("snoozed_due", r#"{"snoozed": "due"}"#),
+    ("tags_notes", r#"{"tags": {"include": ["later"], "exclude": ["done"]}, "notes": "benchmark"}"#),
+    ("error_cursor_and_page", r#"{"cursor": "eyJzIjpbInNjb3JlIGRlc2MiXSwiayI6WzEwMF0sImlkIjo1fQ", "pageNumber": 1}"#),
+    ("error_relevance_without_search", r#"{"sort": [{"sort": "relevance", "asc": false}]}"#),
+];
+
+/// The queries built for the filter, with parameter types in place of their values
+fn render(filter: &str) -> String {
+    let model: StoryRankingFilter = serde_json::from_str(filter).expect("filter");
+    let mut out = format!("-- filter: {}\n", filter.split_whitespace().collect::<Vec<_>>().join(" "));
+    match get_query(&model, 7, &DomainNormalizer::new(None, vec![])) {
+        Ok(q) => {
+            out.push_str(&format!("-- parameters: {:?}\n{}\n\n", redacted_parameters(&q.parameters), q.query));
+            let count_parameters = redacted_parameters(&q.parameters[..q.count_parameters]);
+            out.push_str(&format!("-- count parameters: {:?}\n{}\n\n", count_parameters, q.count_query));
+            out.push_str(&format!("-- estimate\n{}\n", q.estimate_query));
+        }
+        Err(e) => out.push_str(&format!("-- error: {:?}\n", e)),
+    }
+    out
+}
+
+fn assert_snapshot(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/snapshots").join(format!("{}.sql", name));
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {}, run with UPDATE_SNAPSHOTS=1 to create it", path.display(), e));
+    assert!(expected == actual, "{} differs from the built query:\n{}", path.display(), actual);
+}
+
+#[test]
+fn queries() {
+    for (name, filter) in CASES {
+        assert_snapshot(name, &render(filter));
+    }
+}
+
+#[test]
+fn snapshots_have_cases() {
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        return;
+    }
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/snapshots");
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        assert!(CASES.iter().any(|(case, _)| *case == name), "{} has no case", path.display());
+    }
+}
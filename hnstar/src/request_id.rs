@@ -0,0 +1,33 @@
+use actix_web::{HttpMessage, HttpRequest, dev};
+use actix_web::http::header::{HeaderName, HeaderValue};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Identifier given to every request, echoed back in the X-Request-Id header and in error bodies
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Reuse an upstream proxy's request ID when it looks sane, otherwise create a new one
+    pub fn from_service_request(req: &dev::ServiceRequest) -> Self {
+        let upstream = req.headers().get(REQUEST_ID_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= 64 &&
+                id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        match upstream {
+            Some(id) => RequestId(String::from(id)),
+            None => RequestId(uuid::Uuid::new_v4().to_string()),
+        }
+    }
+
+    /// The ID assigned to this request by the middleware
+    pub fn of(req: &HttpRequest) -> String {
+        req.extensions().get::<RequestId>()
+            .map_or_else(|| String::from("unknown"), |id| id.0.clone())
+    }
+
+    pub fn header(&self) -> (HeaderName, HeaderValue) {
+        (HeaderName::from_static(REQUEST_ID_HEADER),
+         HeaderValue::from_str(&self.0).unwrap_or_else(|_| HeaderValue::from_static("unknown")))
+    }
+}
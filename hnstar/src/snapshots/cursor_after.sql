@@ -0,0 +1,98 @@
+-- filter: {"sort": [{"sort": "score", "asc": false}], "cursor": "eyJzIjpbInNjb3JlIGRlc2MiXSwiayI6WzEwMF0sImlkIjo1fQ"}
+-- parameters: ["int", "bigint", "int", "bigint"]
+with full_days as (
+select day, story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares
+            from hnstar.story_daily_stats
+            where (day * 86400 > cast($2 as bigint))
+), stats as (
+select sum(score_sum) / nullif(sum(story_count), 0) mean_x, sqrt(greatest(sum(score_sum_squares) - sum(score_sum) ^ 2 / nullif(sum(story_count), 0), 0) / nullif(sum(story_count) - 1, 0)) stddev_x
+            from (
+                select story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares from full_days
+                union all
+                select count(*) story_count
+    , sum(cast(score as float)) score_sum, sum(cast(score as float) ^ 2) score_sum_squares
+    , sum(ln(greatest(score, 0) + 1)) log_score_sum, sum(ln(greatest(score, 0) + 1) ^ 2) log_score_sum_squares
+                from hnstar.story
+                where (timestamp > $2) and not exists (select 1 from full_days d where d.day = story.timestamp / 86400)
+            ) parts
+), scored_stories as (
+select story.*, stats.mean_x, stats.stddev_x
+                , (cast(story.score as float) - stats.mean_x) / nullif(stats.stddev_x, 0) z_score
+                , 100 * percent_rank() over (order by story.score) percentile
+            from hnstar.story story, stats
+            where (timestamp > $2)
+)
+select s.story_id, s.score, s.timestamp, s.title, s.url
+    , s.status, s.descendants, r.stars, r.flags
+    , cast(s.mean_x as float), cast(s.stddev_x as float)
+    , cast(null as text), cast(null as text)
+    , nullif(s.domain, ''), s.by
+    , s.z_score, s.percentile
+    , cast(extract(epoch from r.snoozed_until) as bigint)
+    , array(
+            select t.name from hnstar.story_tag st join hnstar.tag t on t.tag_id = st.tag_id
+            where st.story_id = s.story_id and t.user_main_id = $1
+            order by t.name)
+    , (select count(*) from hnstar.story_note n where n.story_id = s.story_id and n.user_main_id = $1)
+    , s.score as sort_key_0
+from scored_stories s
+left join hnstar.story_user_rank r on r.story_id = s.story_id and r.user_main_id = $1
+where timestamp > $2 and not coalesce(r.snoozed_until > now(), false) and ((s.score < $3) or (s.score = $3 and s.story_id < $4))
+order by s.score desc, s.story_id desc
+limit 100
+
+-- count parameters: ["int", "bigint"]
+with full_days as (
+select day, story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares
+            from hnstar.story_daily_stats
+            where (day * 86400 > cast($2 as bigint))
+), stats as (
+select sum(score_sum) / nullif(sum(story_count), 0) mean_x, sqrt(greatest(sum(score_sum_squares) - sum(score_sum) ^ 2 / nullif(sum(story_count), 0), 0) / nullif(sum(story_count) - 1, 0)) stddev_x
+            from (
+                select story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares from full_days
+                union all
+                select count(*) story_count
+    , sum(cast(score as float)) score_sum, sum(cast(score as float) ^ 2) score_sum_squares
+    , sum(ln(greatest(score, 0) + 1)) log_score_sum, sum(ln(greatest(score, 0) + 1) ^ 2) log_score_sum_squares
+                from hnstar.story
+                where (timestamp > $2) and not exists (select 1 from full_days d where d.day = story.timestamp / 86400)
+            ) parts
+), scored_stories as (
+select story.*, stats.mean_x, stats.stddev_x
+                , (cast(story.score as float) - stats.mean_x) / nullif(stats.stddev_x, 0) z_score
+                , 100 * percent_rank() over (order by story.score) percentile
+            from hnstar.story story, stats
+            where (timestamp > $2)
+)
+select count(*)
+from scored_stories s
+left join hnstar.story_user_rank r on r.story_id = s.story_id and r.user_main_id = $1
+where timestamp > $2 and not coalesce(r.snoozed_until > now(), false)
+
+-- estimate
+explain (format json) with full_days as (
+select day, story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares
+            from hnstar.story_daily_stats
+            where (day * 86400 > cast($2 as bigint))
+), stats as (
+select sum(score_sum) / nullif(sum(story_count), 0) mean_x, sqrt(greatest(sum(score_sum_squares) - sum(score_sum) ^ 2 / nullif(sum(story_count), 0), 0) / nullif(sum(story_count) - 1, 0)) stddev_x
+            from (
+                select story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares from full_days
+                union all
+                select count(*) story_count
+    , sum(cast(score as float)) score_sum, sum(cast(score as float) ^ 2) score_sum_squares
+    , sum(ln(greatest(score, 0) + 1)) log_score_sum, sum(ln(greatest(score, 0) + 1) ^ 2) log_score_sum_squares
+                from hnstar.story
+                where (timestamp > $2) and not exists (select 1 from full_days d where d.day = story.timestamp / 86400)
+            ) parts
+), scored_stories as (
+select story.*, stats.mean_x, stats.stddev_x
+                , (cast(story.score as float) - stats.mean_x) / nullif(stats.stddev_x, 0) z_score
+                , 100 * percent_rank() over (order by story.score) percentile
+            from hnstar.story story, stats
+            where (timestamp > $2)
+)
+select 1
+from scored_stories s
+left join hnstar.story_user_rank r on r.story_id = s.story_id and r.user_main_id = $1
+where timestamp > $2 and not coalesce(r.snoozed_until > now(), false)
@@ -0,0 +1,2 @@
+-- filter: {"cursor": "eyJzIjpbInNjb3JlIGRlc2MiXSwiayI6WzEwMF0sImlkIjo1fQ", "pageNumber": 1}
+-- error: cursor: "Specify either cursor or pageNumber, not both"
@@ -0,0 +1,2 @@
+-- filter: {"sort": [{"sort": "relevance", "asc": false}]}
+-- error: sort: "Sorting by relevance requires a search"
@@ -0,0 +1,75 @@
+-- filter: {"normalization": "rolling24h", "percentile": {"gt": 50}}
+-- parameters: ["int", "bigint", "float"]
+with rolled as (
+select *, avg(cast(score as float)) over day mean_x, stddev(cast(score as float)) over day stddev_x
+            from hnstar.story
+            where (timestamp > cast($2 as bigint) - 43200)
+            window day as (order by timestamp
+                range between cast(43200 as bigint) preceding and cast(43200 as bigint) following)
+), scored_stories as (
+select rolled.*
+                , (cast(score as float) - mean_x) / nullif(stddev_x, 0) z_score
+                , (select 100 * cast(count(*) filter (where o.score < rolled.score) as float) / nullif(count(*) - 1, 0)
+                   from hnstar.story o
+                   where o.timestamp between rolled.timestamp - 43200 and rolled.timestamp + 43200) percentile
+            from rolled
+)
+select s.story_id, s.score, s.timestamp, s.title, s.url
+    , s.status, s.descendants, r.stars, r.flags
+    , cast(s.mean_x as float), cast(s.stddev_x as float)
+    , cast(null as text), cast(null as text)
+    , nullif(s.domain, ''), s.by
+    , s.z_score, s.percentile
+    , cast(extract(epoch from r.snoozed_until) as bigint)
+    , array(
+            select t.name from hnstar.story_tag st join hnstar.tag t on t.tag_id = st.tag_id
+            where st.story_id = s.story_id and t.user_main_id = $1
+            order by t.name)
+    , (select count(*) from hnstar.story_note n where n.story_id = s.story_id and n.user_main_id = $1)
+    , s.timestamp as sort_key_0
+from scored_stories s
+left join hnstar.story_user_rank r on r.story_id = s.story_id and r.user_main_id = $1
+where timestamp > $2 and s.percentile >= $3 and not coalesce(r.snoozed_until > now(), false)
+order by s.timestamp desc, s.story_id desc
+limit 100
+offset 0
+
+-- count parameters: ["int", "bigint", "float"]
+with rolled as (
+select *, avg(cast(score as float)) over day mean_x, stddev(cast(score as float)) over day stddev_x
+            from hnstar.story
+            where (timestamp > cast($2 as bigint) - 43200)
+            window day as (order by timestamp
+                range between cast(43200 as bigint) preceding and cast(43200 as bigint) following)
+), scored_stories as (
+select rolled.*
+                , (cast(score as float) - mean_x) / nullif(stddev_x, 0) z_score
+                , (select 100 * cast(count(*) filter (where o.score < rolled.score) as float) / nullif(count(*) - 1, 0)
+                   from hnstar.story o
+                   where o.timestamp between rolled.timestamp - 43200 and rolled.timestamp + 43200) percentile
+            from rolled
+)
+select count(*)
+from scored_stories s
+left join hnstar.story_user_rank r on r.story_id = s.story_id and r.user_main_id = $1
+where timestamp > $2 and s.percentile >= $3 and not coalesce(r.snoozed_until > now(), false)
+
+-- estimate
+explain (format json) with rolled as (
+select *, avg(cast(score as float)) over day mean_x, stddev(cast(score as float)) over day stddev_x
+            from hnstar.story
+            where (timestamp > cast($2 as bigint) - 43200)
+            window day as (order by timestamp
+                range between cast(43200 as bigint) preceding and cast(43200 as bigint) following)
+), scored_stories as (
+select rolled.*
+                , (cast(score as float) - mean_x) / nullif(stddev_x, 0) z_score
+                , (select 100 * cast(count(*) filter (where o.score < rolled.score) as float) / nullif(count(*) - 1, 0)
+                   from hnstar.story o
+                   where o.timestamp between rolled.timestamp - 43200 and rolled.timestamp + 43200) percentile
+            from rolled
+)
+select 1
+from scored_stories s
+left join hnstar.story_user_rank r on r.story_id = s.story_id and r.user_main_id = $1
+where timestamp > $2 and s.percentile >= $3 and not coalesce(r.snoozed_until > now(), false)
@@ -0,0 +1,384 @@
+//! Typed pieces of the SQL the story queries are assembled from.
+//!
+//! Values are only ever bound through `Parameters`, which numbers the placeholders, so clauses can be built in any
+//! order and combined without counting `$n` by hand.
+
+use postgres_types::ToSql;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum SqlParameter {
+    Int(i32),
+    IntArray(Vec<i32>),
+    BigInt(i64),
+    BigIntArray(Vec<i64>),
+    Float(f64),
+    Varchar(String),
+    VarcharArray(Vec<String>),
+}
+
+impl From<i64> for SqlParameter { fn from(i: i64) -> Self { SqlParameter::BigInt(i) } }
+
+impl From<i32> for SqlParameter { fn from(i: i32) -> Self { SqlParameter::Int(i) } }
+
+impl From<Vec<i32>> for SqlParameter { fn from(v: Vec<i32>) -> Self { SqlParameter::IntArray(v) } }
+
+impl From<Vec<i64>> for SqlParameter { fn from(v: Vec<i64>) -> Self { SqlParameter::BigIntArray(v) } }
+
+impl From<f64> for SqlParameter { fn from(f: f64) -> Self { SqlParameter::Float(f) } }
+
+impl From<String> for SqlParameter { fn from(s: String) -> Self { SqlParameter::Varchar(s) } }
+
+impl From<Vec<String>> for SqlParameter { fn from(v: Vec<String>) -> Self { SqlParameter::VarcharArray(v) } }
+
+impl SqlParameter {
+    /// Name of the parameter type, for logging queries without their values
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            SqlParameter::Int(_) => "int",
+            SqlParameter::IntArray(_) => "int[]",
+            SqlParameter::BigInt(_) => "bigint",
+            SqlParameter::BigIntArray(_) => "bigint[]",
+            SqlParameter::Float(_) => "float",
+            SqlParameter::Varchar(_) => "varchar",
+            SqlParameter::VarcharArray(_) => "varchar[]",
+        }
+    }
+
+    pub fn to_dynamic(&self) -> &(dyn ToSql + Sync) {
+        match self {
+            SqlParameter::Int(i) => i as &(dyn ToSql + Sync),
+            SqlParameter::IntArray(v) => v as &(dyn ToSql + Sync),
+            SqlParameter::BigInt(i) => i as &(dyn ToSql + Sync),
+            SqlParameter::BigIntArray(v) => v as &(dyn ToSql + Sync),
+            SqlParameter::Float(f) => f as &(dyn ToSql + Sync),
+            SqlParameter::Varchar(v) => v as &(dyn ToSql + Sync),
+            SqlParameter::VarcharArray(v) => v as &(dyn ToSql + Sync),
+        }
+    }
+}
+
+/// A bound value's `$n`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Placeholder(usize);
+
+impl fmt::Display for Placeholder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${}", self.0)
+    }
+}
+
+/// The values of a query, in placeholder order
+#[derive(Debug, Default)]
+pub struct Parameters(Vec<SqlParameter>);
+
+impl Parameters {
+    pub fn new() -> Self {
+        Parameters(vec![])
+    }
+
+    pub fn bind<T: Into<SqlParameter>>(&mut self, value: T) -> Placeholder {
+        self.0.push(value.into());
+        Placeholder(self.0.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn into_vec(self) -> Vec<SqlParameter> {
+        self.0
+    }
+}
+
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Comparison {
+    Eq,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Comparison {
+    pub fn sql(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+        }
+    }
+
+    /// The comparison with its sides swapped, e.g. `a < b` as `b > a`
+    pub fn flipped(&self) -> Comparison {
+        match self {
+            Comparison::Eq => Comparison::Eq,
+            Comparison::Gt => Comparison::Lt,
+            Comparison::Ge => Comparison::Le,
+            Comparison::Lt => Comparison::Gt,
+            Comparison::Le => Comparison::Ge,
+        }
+    }
+}
+
+/// A boolean SQL expression
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    /// SQL that already is a boolean expression, with any values bound by the caller
+    Sql(String),
+    Compare(String, Comparison, Placeholder),
+    /// True when every predicate is, so true when there are none
+    All(Vec<Predicate>),
+    /// True when some predicate is, so false when there are none
+    Any(Vec<Predicate>),
+    /// True unless the predicate is, so unknowns like comparisons with null count as false
+    Not(Box<Predicate>),
+}
+
+impl Predicate {
+    pub fn sql<S: Into<String>>(sql: S) -> Self {
+        Predicate::Sql(sql.into())
+    }
+
+    pub fn compare<S: Into<String>>(expression: S, comparison: Comparison, placeholder: Placeholder) -> Self {
+        Predicate::Compare(expression.into(), comparison, placeholder)
+    }
+
+    pub fn negate(self) -> Self {
+        Predicate::Not(Box::new(self))
+    }
+
+    pub fn to_sql(&self) -> String {
+        match self {
+            Predicate::Sql(sql) => sql.clone(),
+            Predicate::Compare(expression, comparison, placeholder) =>
+                format!("{} {} {}", expression, comparison.sql(), placeholder),
+            Predicate::All(terms) if terms.is_empty() => String::from("true"),
+            Predicate::Any(terms) if terms.is_empty() => String::from("false"),
+            Predicate::All(terms) => format!("({})", conjunction(terms)),
+            Predicate::Any(terms) => format!("({})", terms.iter().map(Predicate::to_sql).collect::<Vec<_>>().join(" or ")),
+            Predicate::Not(term) => format!("not coalesce({}, false)", term.to_sql()),
+        }
+    }
+}
+
+/// The predicates and'ed together, without the parentheses a nested `Predicate::All` gets
+fn conjunction(terms: &[Predicate]) -> String {
+    terms.iter().map(Predicate::to_sql).collect::<Vec<_>>().join(" and ")
+}
+
+#[derive(Clone, Debug)]
+pub enum Join {
+    Left(String, Vec<Predicate>),
+    /// A table or function whose single row every row is paired with, e.g. the parsed search query
+    Cross(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct OrderBy {
+    pub expression: String,
+    pub asc: bool,
+}
+
+/// A select statement, rendered by `to_sql` or as the count of its rows by `count_sql`
+#[derive(Clone, Debug, Default)]
+pub struct Select {
+    with: Vec<(String, String)>,
+    columns: Vec<String>,
+    from: String,
+    joins: Vec<Join>,
+    filter: Vec<Predicate>,
+    group_by: Vec<String>,
+    having: Vec<Predicate>,
+    order_by: Vec<OrderBy>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl Select {
+    pub fn from<S: Into<String>>(from: S) -> Self {
+        Select { from: from.into(), ..Default::default() }
+    }
+
+    /// Defines a common table expression the query can select from
+    pub fn with<S: Into<String>>(&mut self, name: &str, query: S) -> &mut Self {
+        self.with.push((String::from(name), query.into()));
+        self
+    }
+
+    pub fn column<S: Into<String>>(&mut self, expression: S) -> &mut Self {
+        self.columns.push(expression.into());
+        self
+    }
+
+    pub fn join(&mut self, join: Join) -> &mut Self {
+        self.joins.push(join);
+        self
+    }
+
+    /// Adds a predicate every row must match
+    pub fn filter(&mut self, predicate: Predicate) -> &mut Self {
+        self.filter.push(predicate);
+        self
+    }
+
+    pub fn group_by<S: Into<String>>(&mut self, expression: S) -> &mut Self {
+        self.group_by.push(expression.into());
+        self
+    }
+
+    pub fn having(&mut self, predicate: Predicate) -> &mut Self {
+        self.having.push(predicate);
+        self
+    }
+
+    pub fn order_by<S: Into<String>>(&mut self, expression: S, asc: bool) -> &mut Self {
+        self.order_by.push(OrderBy { expression: expression.into(), asc });
+        self
+    }
+
+    pub fn limit(&mut self, limit: i64) -> &mut Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(&mut self, offset: i64) -> &mut Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn to_sql(&self) -> String {
+        let mut sql = self.head(&self.columns.join("\n    , "));
+        if !self.group_by.is_empty() {
+            sql.push_str(&format!("\ngroup by {}", self.group_by.join(", ")));
+        }
+        if !self.having.is_empty() {
+            sql.push_str(&format!("\nhaving {}", conjunction(&self.having)));
+        }
+        if !self.order_by.is_empty() {
+            let order_by: Vec<String> = self.order_by.iter()
+                .map(|o| format!("{} {}", o.expression, if o.asc { "asc" } else { "desc" }))
+                .collect();
+            sql.push_str(&format!("\norder by {}", order_by.join(", ")));
+        }
+        if let Some(limit) = self.limit {
+            sql.push_str(&format!("\nlimit {}", limit));
+        }
+        if let Some(offset) = self.offset {
+            sql.push_str(&format!("\noffset {}", offset));
+        }
+        sql
+    }
+
+    /// Counts the rows the query filters, ignoring grouping, sorting and paging
+    pub fn count_sql(&self) -> String {
+        self.head("count(*)")
+    }
+
+    /// Selects a constant for every row the query filters, ignoring grouping, sorting and paging, for the planner to
+    /// estimate the count of with `explain`
+    pub fn rows_sql(&self) -> String {
+        self.head("1")
+    }
+
+    /// Selects the columns of every row the query filters, ignoring grouping, sorting and paging
+    pub fn filtered_sql(&self, columns: &str) -> String {
+        self.head(columns)
+    }
+
+    fn head(&self, columns: &str) -> String {
+        let mut sql = String::new();
+        if !self.with.is_empty() {
+            let with: Vec<String> = self.with.iter()
+                .map(|(name, query)| format!("{} as (\n{}\n)", name, query.trim()))
+                .collect();
+            sql.push_str(&format!("with {}\n", with.join(", ")));
+        }
+        sql.push_str(&format!("select {}\nfrom {}", columns, self.from));
+        for join in &self.joins {
+            match join {
+                Join::Left(table, on) => sql.push_str(&format!("\nleft join {} on {}", table, conjunction(on))),
+                Join::Cross(table) => sql.push_str(&format!("\ncross join {}", table)),
+            }
+        }
+        if !self.filter.is_empty() {
+            sql.push_str(&format!("\nwhere {}", conjunction(&self.filter)));
+        }
+        sql
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn predicates() {
+        let mut parameters = Parameters::new();
+        let a = parameters.bind(1);
+        let b = parameters.bind(String::from("x"));
+        let compare = Predicate::compare("s.score", Comparison::Gt, a);
+        let text = Predicate::sql(format!("s.title ~* {}", b));
+
+        assert_eq!(compare.to_sql(), "s.score > $1");
+        assert_eq!(Predicate::All(vec![]).to_sql(), "true");
+        assert_eq!(Predicate::Any(vec![]).to_sql(), "false");
+        assert_eq!(Predicate::All(vec![compare.clone(), text.clone()]).to_sql(), "(s.score > $1 and s.title ~* $2)");
+        assert_eq!(Predicate::Any(vec![compare.clone(), text]).to_sql(), "(s.score > $1 or s.title ~* $2)");
+        assert_eq!(compare.negate().to_sql(), "not coalesce(s.score > $1, false)");
+        assert_eq!(Comparison::Lt.flipped(), Comparison::Gt);
+        assert_eq!(parameters.len(), 2);
+    }
+
+    #[test]
+    fn select() {
+        let mut parameters = Parameters::new();
+        let user = parameters.bind(7);
+        let mut query = Select::from("hnstar.story s");
+        query.with("recent", "\n    select story_id from hnstar.story where timestamp > 0\n")
+            .column("s.story_id")
+            .column("count(t.tag_id)")
+            .join(Join::Left(String::from("hnstar.story_tag t"), vec![
+                Predicate::sql("t.story_id = s.story_id"),
+                Predicate::compare("t.user_main_id", Comparison::Eq, user),
+            ]))
+            .join(Join::Cross(String::from("websearch_to_tsquery('rust') query")))
+            .filter(Predicate::sql("s.story_id in (select story_id from recent)"))
+            .filter(Predicate::Any(vec![Predicate::sql("s.dead"), Predicate::sql("s.deleted")]).negate())
+            .group_by("s.story_id")
+            .having(Predicate::sql("count(t.tag_id) > 1"))
+            .order_by("s.story_id", false)
+            .limit(10)
+            .offset(20);
+
+        let head = "with recent as (
+select story_id from hnstar.story where timestamp > 0
+)
+select {}
+from hnstar.story s
+left join hnstar.story_tag t on t.story_id = s.story_id and t.user_main_id = $1
+cross join websearch_to_tsquery('rust') query
+where s.story_id in (select story_id from recent) and not coalesce((s.dead or s.deleted), false)";
+        assert_eq!(query.to_sql(), head.replace("{}", "s.story_id\n    , count(t.tag_id)") + "
+group by s.story_id
+having count(t.tag_id) > 1
+order by s.story_id desc
+limit 10
+offset 20");
+        assert_eq!(query.count_sql(), head.replace("{}", "count(*)"));
+        assert_eq!(query.rows_sql(), head.replace("{}", "1"));
+        assert_eq!(query.filtered_sql("s.story_id"), head.replace("{}", "s.story_id"));
+    }
+
+    #[test]
+    fn bare_select() {
+        let mut query = Select::from("hnstar.story s");
+        query.column("s.story_id").order_by("s.score", true);
+        assert_eq!(query.to_sql(), "select s.story_id\nfrom hnstar.story s\norder by s.score asc");
+        assert_eq!(query.count_sql(), "select count(*)\nfrom hnstar.story s");
+    }
+}
@@ -0,0 +1,18 @@
+-- Full-text search over story titles, story domains and users' notes.
+-- Domains are indexed whole (github.com) and split (github com) so either form matches.
+alter table hnstar.story
+    add column search tsvector generated always as (
+        setweight(to_tsvector('english', coalesce(title, '')), 'A') ||
+        setweight(to_tsvector('simple', coalesce(
+            substring(url from '^[a-zA-Z]+://(?:www\.)?([^/:?#]+)') || ' ' ||
+            replace(substring(url from '^[a-zA-Z]+://(?:www\.)?([^/:?#]+)'), '.', ' '), '')), 'B')
+    ) stored;
+
+create index story_search_idx on hnstar.story using gin (search);
+
+alter table hnstar.story_user_rank
+    add column search tsvector generated always as (
+        setweight(to_tsvector('english', coalesce(comment, '')), 'C')
+    ) stored;
+
+create index story_user_rank_search_idx on hnstar.story_user_rank using gin (search);
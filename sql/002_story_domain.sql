@@ -0,0 +1,5 @@
+-- Normalized site of each story, e.g. bbc.co.uk or github.com/user, derived by hnstar-sync.
+-- Null until derived; empty for stories without a URL. Existing stories are backfilled on the next sync.
+alter table hnstar.story add column domain text;
+
+create index story_domain_idx on hnstar.story (domain);
@@ -0,0 +1,30 @@
+-- Score and comment statistics per UTC day (day = timestamp / 86400), maintained by hnstar-sync.
+-- Sums rather than means are stored so any range of days can be combined:
+--   mean = sum / count, stddev = sqrt((sum_squares - sum^2 / count) / (count - 1)).
+-- Quantiles are at 0.1, 0.25, 0.5, 0.75, 0.9 and 0.99 and cannot be combined across days.
+create table hnstar.story_daily_stats (
+    day bigint primary key,
+    story_count bigint not null,
+    score_sum double precision not null,
+    score_sum_squares double precision not null,
+    log_score_sum double precision not null,
+    log_score_sum_squares double precision not null,
+    score_quantiles double precision[] not null,
+    descendants_sum double precision not null,
+    descendants_sum_squares double precision not null,
+    descendants_quantiles double precision[] not null,
+    updated timestamp not null default now()
+);
+
+-- Days synced before the statistics were; hnstar-sync refreshes only the days of the stories it syncs.
+insert into hnstar.story_daily_stats (
+    day, story_count, score_sum, score_sum_squares, log_score_sum, log_score_sum_squares, score_quantiles,
+    descendants_sum, descendants_sum_squares, descendants_quantiles)
+select timestamp / 86400, count(*)
+    , sum(score), sum(cast(score as float) ^ 2)
+    , sum(ln(greatest(score, 0) + 1)), sum(ln(greatest(score, 0) + 1) ^ 2)
+    , percentile_cont(array[0.1, 0.25, 0.5, 0.75, 0.9, 0.99]) within group (order by score)
+    , sum(descendants), sum(cast(descendants as float) ^ 2)
+    , percentile_cont(array[0.1, 0.25, 0.5, 0.75, 0.9, 0.99]) within group (order by descendants)
+from hnstar.story
+group by timestamp / 86400;
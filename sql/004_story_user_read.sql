@@ -0,0 +1,6 @@
+-- When the user last marked stories read through /ranks/read, for the "new since last visit" filter. The read flag
+-- (2) of story_user_rank is the read state of each story; this is only when they last caught up.
+create table hnstar.user_last_read (
+    user_main_id integer primary key,
+    read timestamp with time zone not null
+);
@@ -0,0 +1,5 @@
+-- Until when the user snoozed the story; /ranks/query leaves snoozed stories out until the time passes.
+alter table hnstar.story_user_rank add column snoozed_until timestamp with time zone;
+
+create index story_user_rank_snoozed_idx on hnstar.story_user_rank (user_main_id, snoozed_until)
+    where snoozed_until is not null;
@@ -0,0 +1,18 @@
+-- Tags users put on stories. Names are lowercase and unique per user; tagging only the stories of a tag's owner
+-- means story_tag needs no user of its own.
+create table hnstar.tag (
+    tag_id serial primary key,
+    user_main_id integer not null,
+    name text not null,
+    created timestamp not null default now(),
+    unique (user_main_id, name)
+);
+
+create table hnstar.story_tag (
+    story_id bigint not null references hnstar.story (story_id),
+    tag_id integer not null references hnstar.tag (tag_id) on delete cascade,
+    created timestamp not null default now(),
+    primary key (story_id, tag_id)
+);
+
+create index story_tag_tag_idx on hnstar.story_tag (tag_id);
@@ -0,0 +1,22 @@
+-- Notes users write on stories in Markdown, rendered to sanitized HTML when read. Editing a note keeps its previous
+-- text as a revision. The single story_user_rank.comment stays as it was.
+create table hnstar.story_note (
+    note_id serial primary key,
+    user_main_id integer not null,
+    story_id bigint not null references hnstar.story (story_id),
+    body text not null,
+    created timestamp not null default now(),
+    updated timestamp not null default now(),
+    search tsvector generated always as (to_tsvector('english', body)) stored
+);
+
+create index story_note_story_idx on hnstar.story_note (user_main_id, story_id);
+create index story_note_search_idx on hnstar.story_note using gin (search);
+
+create table hnstar.story_note_revision (
+    note_id integer not null references hnstar.story_note (note_id) on delete cascade,
+    revision integer not null,
+    body text not null,
+    created timestamp not null,
+    primary key (note_id, revision)
+);
@@ -0,0 +1,41 @@
+-- Append-only log of every change to story_user_rank, written by a trigger so all endpoints are covered. Rows are
+-- stored as JSON before and after the change: no before for a created ranking, no after for a deleted one.
+-- Undoing a change restores its before state with hnstar.undoes set to the change's log_id, which the trigger records
+-- on the entry of the restore, so undone changes and the undos themselves are not undone again.
+create table hnstar.story_user_rank_log (
+    log_id bigserial primary key,
+    user_main_id integer not null,
+    story_id bigint not null,
+    before jsonb,
+    after jsonb,
+    undoes bigint references hnstar.story_user_rank_log (log_id),
+    created timestamp not null default now()
+);
+
+create index story_user_rank_log_user_idx on hnstar.story_user_rank_log (user_main_id, log_id);
+create index story_user_rank_log_undoes_idx on hnstar.story_user_rank_log (undoes) where undoes is not null;
+
+create function hnstar.log_story_user_rank() returns trigger language plpgsql as $$
+begin
+    insert into hnstar.story_user_rank_log (user_main_id, story_id, before, after, undoes)
+    values (
+        coalesce(new.user_main_id, old.user_main_id),
+        coalesce(new.story_id, old.story_id),
+        case when tg_op = 'INSERT' then null else to_jsonb(old) - 'search' end,
+        case when tg_op = 'DELETE' then null else to_jsonb(new) - 'search' end,
+        cast(nullif(current_setting('hnstar.undoes', true), '') as bigint));
+    return null;
+end
+$$;
+
+create trigger story_user_rank_log after insert or update or delete on hnstar.story_user_rank
+    for each row execute function hnstar.log_story_user_rank();
+
+create function hnstar.reject_log_change() returns trigger language plpgsql as $$
+begin
+    raise exception 'story_user_rank_log is append-only';
+end
+$$;
+
+create trigger story_user_rank_log_append_only before update or delete or truncate on hnstar.story_user_rank_log
+    for each statement execute function hnstar.reject_log_change();